@@ -0,0 +1,107 @@
+use ::*;
+
+use sha2::{Digest, Sha256};
+
+pub const ACCOUNTS_FILE: &str = "accounts.txt";
+
+const HASH_ROUNDS: usize = 10000;
+
+struct Account {
+    salt: String,
+    hash: String,
+}
+
+fn hash(salt: &str, password: &str) -> String {
+    let mut digest = Sha256::digest(format!("{}:{}", salt, password).as_bytes()).to_vec();
+    for _ in 1..HASH_ROUNDS {
+        digest.extend(salt.as_bytes());
+        digest = Sha256::digest(&digest).to_vec();
+    }
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Compares in time that does not depend on where the hashes differ.
+fn same_hash(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Registered nicknames with salted password hashes,
+// stored one "nick salt hash" line per account.
+pub struct Accounts {
+    path: std::path::PathBuf,
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    // Starts with no accounts if the file does not exist yet.
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        use std::io::Read;
+        let path = path.as_ref().to_owned();
+        let mut accounts = HashMap::new();
+        let mut text = String::new();
+        match std::fs::File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut text)?;
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        for (index, line) in text.lines().enumerate() {
+            let args = split_args(line).unwrap_or_default();
+            if args.is_empty() {
+                continue;
+            }
+            if args.len() != 3 {
+                eprintln!("Skipping bad account at {:?} line {}", path, index + 1);
+                continue;
+            }
+            let mut args = args.into_iter();
+            let nick = args.next().unwrap();
+            accounts.insert(nick, Account {
+                salt: args.next().unwrap(),
+                hash: args.next().unwrap(),
+            });
+        }
+        eprintln!("Loaded {} accounts from {:?}", accounts.len(), path);
+        Ok(Self { path, accounts })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        use std::io::Write;
+        let mut nicks: Vec<&String> = self.accounts.keys().collect();
+        nicks.sort();
+        let mut text = String::new();
+        for nick in nicks {
+            let account = &self.accounts[nick];
+            text += &format!("{} {} {}\n", quote(nick), account.salt, account.hash);
+        }
+        // Written to a temporary file first so a crash can not lose every account.
+        let temp = self.path.with_extension("tmp");
+        std::fs::File::create(&temp)?.write_all(text.as_bytes())?;
+        std::fs::rename(&temp, &self.path)
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.accounts.contains_key(nick)
+    }
+
+    pub fn register(&mut self, nick: &str, password: &str) -> Result<(), RejectReason> {
+        if self.is_registered(nick) {
+            return Err(RejectReason::NickTaken);
+        }
+        let salt = format!("{:016x}{:016x}", random::<u64>(), random::<u64>());
+        let hash = hash(&salt, password);
+        self.accounts.insert(nick.to_owned(), Account { salt, hash });
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save accounts to {:?}: {}", self.path, e);
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, nick: &str, password: &str) -> bool {
+        match self.accounts.get(nick) {
+            Some(account) => same_hash(&hash(&account.salt, password), &account.hash),
+            None => false,
+        }
+    }
+}
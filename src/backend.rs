@@ -0,0 +1,1052 @@
+use ::*;
+
+use map::{GameMap, DEFAULT_MAP};
+use mapgen;
+
+const MAX_PLAYERS: usize = 6;
+
+pub fn send(output: &mut Vec<String>, nicks: &[String], message: ServerMessage) {
+    if !nicks.is_empty() {
+        let nicks: Vec<String> = nicks.iter().map(|nick| quote(nick)).collect();
+        output.push(format!("{}:{}", nicks.join(","), message.to_wire()));
+    }
+}
+
+struct Player {
+    nick: String,
+    color: char,
+    ready: bool,
+}
+
+enum Phase {
+    Attack {
+        selected: Option<Vec2<usize>>,
+    },
+    // Upgrades made so far, as (cell, trolls added), so that they can be undone.
+    Upgrade {
+        energy: usize,
+        upgrades: Vec<(Vec2<usize>, usize)>,
+    },
+}
+
+struct Match {
+    map: Vec<Vec<Option<GameCell>>>,
+    order: Vec<(String, char)>,
+    current: usize,
+    phase: Phase,
+    deadline: Option<std::time::Instant>,
+}
+
+impl Match {
+    // Slot 'A' of the map goes to the first player to join,
+    // 'B' to the second and so on. Unused slots become empty land.
+    fn new(players: &[Player], map: &GameMap) -> Self {
+        let map = map.cells.iter().map(|line| {
+            line.iter().map(|&cell| {
+                match cell {
+                    Some(GameCell::Populated { count, owner }) => {
+                        let slot = (owner as u8 - b'A') as usize;
+                        Some(match players.get(slot) {
+                            Some(player) => GameCell::Populated { count, owner: player.color },
+                            None => GameCell::Empty,
+                        })
+                    }
+                    cell => cell,
+                }
+            }).collect()
+        }).collect();
+        Self {
+            map,
+            order: players.iter().map(|player| (player.nick.clone(), player.color)).collect(),
+            current: 0,
+            phase: Phase::Attack { selected: None },
+            deadline: None,
+        }
+    }
+
+    fn time_left(&self) -> Option<f64> {
+        self.deadline.map(|deadline| {
+            let now = std::time::Instant::now();
+            let left = if deadline > now { deadline - now } else { std::time::Duration::from_secs(0) };
+            left.as_secs() as f64 + left.subsec_nanos() as f64 * 1e-9
+        })
+    }
+
+    fn current_nick(&self) -> &str {
+        &self.order[self.current].0
+    }
+
+    fn current_color(&self) -> char {
+        self.order[self.current].1
+    }
+
+    fn cell(&self, pos: Vec2<usize>) -> Option<GameCell> {
+        self.map.get(pos.x).and_then(|line| line.get(pos.y)).and_then(|&cell| cell)
+    }
+
+    fn set_cell(&mut self, pos: Vec2<usize>, cell: GameCell) {
+        self.map[pos.x][pos.y] = Some(cell);
+    }
+
+    fn owned_by(&self, pos: Vec2<usize>, color: char) -> Option<usize> {
+        match self.cell(pos) {
+            Some(GameCell::Populated { count, owner }) if owner == color => Some(count),
+            _ => None,
+        }
+    }
+
+    fn cell_count(&self, color: char) -> usize {
+        self.map.iter().flat_map(|line| line.iter()).filter(|cell| {
+            match **cell {
+                Some(GameCell::Populated { owner, .. }) => owner == color,
+                _ => false,
+            }
+        }).count()
+    }
+
+    fn alive(&self) -> Vec<usize> {
+        (0..self.order.len()).filter(|&index| self.cell_count(self.order[index].1) > 0).collect()
+    }
+
+    fn can_move(&self, from: Vec2<usize>) -> Vec<Vec2<usize>> {
+        let color = self.current_color();
+        match self.owned_by(from, color) {
+            Some(count) if count >= 2 => {}
+            _ => return Vec::new(),
+        }
+        neighbours(from).into_iter().filter(|&pos| {
+            match self.cell(pos) {
+                Some(GameCell::Empty) => true,
+                Some(GameCell::Populated { owner, .. }) => owner != color,
+                None => false,
+            }
+        }).collect()
+    }
+
+    // All trolls but one leave the source cell. Against an enemy cell every
+    // attacker kills one defender; whoever has trolls left holds the cell.
+    fn attack(&mut self, from: Vec2<usize>, to: Vec2<usize>) {
+        let color = self.current_color();
+        let attackers = self.owned_by(from, color).unwrap() - 1;
+        self.set_cell(from, GameCell::Populated { count: 1, owner: color });
+        let target = match self.cell(to).unwrap() {
+            GameCell::Empty => GameCell::Populated { count: attackers, owner: color },
+            GameCell::Populated { count, owner } => {
+                if attackers > count {
+                    GameCell::Populated { count: attackers - count, owner: color }
+                } else {
+                    GameCell::Populated { count: std::cmp::max(count - attackers, 1), owner }
+                }
+            }
+        };
+        self.set_cell(to, target);
+    }
+
+    fn remove_player(&mut self, color: char) {
+        for line in &mut self.map {
+            for cell in line.iter_mut() {
+                if let Some(GameCell::Populated { owner, .. }) = *cell {
+                    if owner == color {
+                        *cell = Some(GameCell::Empty);
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum RoomMap {
+    Fixed(GameMap),
+    Generated {
+        seed: u64,
+        size: usize,
+        density: f64,
+    },
+}
+
+struct Room {
+    map: RoomMap,
+    // None if turns are not limited.
+    turn_time: Option<std::time::Duration>,
+    players: Vec<Player>,
+    spectators: Vec<String>,
+    game: Option<Match>,
+}
+
+impl Room {
+    fn new(map: RoomMap, turn_time: Option<std::time::Duration>) -> Self {
+        Self {
+            map,
+            turn_time,
+            players: Vec::new(),
+            spectators: Vec::new(),
+            game: None,
+        }
+    }
+
+    fn members(&self) -> Vec<String> {
+        self.players.iter().map(|player| player.nick.clone())
+            .chain(self.spectators.iter().cloned())
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.players.is_empty() && self.spectators.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        let slots = match self.map {
+            RoomMap::Fixed(ref map) => map.info.players,
+            RoomMap::Generated { .. } => MAX_PLAYERS,
+        };
+        self.players.len() >= std::cmp::min(MAX_PLAYERS, slots)
+    }
+
+    fn free_color(&self) -> Option<char> {
+        (0..MAX_PLAYERS as u8)
+            .map(|index| (b'A' + index) as char)
+            .find(|&color| !self.players.iter().any(|player| player.color == color))
+    }
+
+    fn send_map(&self, output: &mut Vec<String>) {
+        let members = self.members();
+        self.send_map_to(output, &members);
+    }
+
+    fn send_map_to(&self, output: &mut Vec<String>, nicks: &[String]) {
+        if let Some(ref game) = self.game {
+            for (index, line) in game.map.iter().enumerate() {
+                send(output, nicks, ServerMessage::MapLine(index, line.clone()));
+            }
+            send(output, nicks, ServerMessage::EndMap);
+        }
+    }
+
+    fn send_state_to(&self, output: &mut Vec<String>, nick: &str) {
+        let nicks = [nick.to_owned()];
+        if let Some(ref game) = self.game {
+            send(output, &nicks, ServerMessage::GameStart);
+            for &(ref player, color) in &game.order {
+                send(output, &nicks, ServerMessage::PlayerColor { nick: player.clone(), color });
+            }
+            self.send_map_to(output, &nicks);
+            send(output, &nicks, ServerMessage::Turn {
+                nick: game.current_nick().to_owned(),
+                time_left: game.time_left(),
+            });
+            match game.phase {
+                Phase::Attack { selected: Some(pos) } => {
+                    send(output, &nicks, ServerMessage::SelectCell { row: pos.x, col: pos.y });
+                }
+                Phase::Attack { selected: None } => {}
+                Phase::Upgrade { energy, ref upgrades } => {
+                    send(output, &nicks, ServerMessage::UpgradePhase);
+                    send(output, &nicks, ServerMessage::EnergyLeft(energy));
+                    send(output, &nicks, ServerMessage::PendingUpgrades(upgrades.len()));
+                }
+            }
+        }
+    }
+
+    fn try_start(&mut self, output: &mut Vec<String>) {
+        if self.game.is_some() || self.players.len() < 2 || !self.players.iter().all(|player| player.ready) {
+            return;
+        }
+        let members = self.members();
+        let game = match self.map {
+            RoomMap::Fixed(ref map) => Match::new(&self.players, map),
+            RoomMap::Generated { seed, size, density } => {
+                Match::new(&self.players, &mapgen::generate_map(self.players.len(), seed, size, density))
+            }
+        };
+        send(output, &members, ServerMessage::GameStart);
+        for &(ref nick, color) in &game.order {
+            send(output, &members, ServerMessage::PlayerColor { nick: nick.clone(), color });
+        }
+        self.game = Some(game);
+        self.send_map(output);
+        self.begin_turn(output);
+    }
+
+    fn begin_turn(&mut self, output: &mut Vec<String>) {
+        let members = self.members();
+        let game = self.game.as_mut().unwrap();
+        game.phase = Phase::Attack { selected: None };
+        game.deadline = self.turn_time.map(|turn_time| std::time::Instant::now() + turn_time);
+        send(output, &members, ServerMessage::Turn {
+            nick: game.current_nick().to_owned(),
+            time_left: game.time_left(),
+        });
+    }
+
+    fn is_turn_expired(&self) -> bool {
+        match self.game {
+            Some(Match { deadline: Some(deadline), .. }) => std::time::Instant::now() >= deadline,
+            _ => false,
+        }
+    }
+
+    // Moves on as if the current player had clicked through the rest of their turn.
+    fn expire_turn(&mut self, output: &mut Vec<String>) {
+        if let Phase::Attack { .. } = self.game.as_ref().unwrap().phase {
+            self.next_phase(output);
+        }
+        self.next_phase(output);
+    }
+
+    fn next_turn(&mut self, output: &mut Vec<String>) {
+        {
+            let game = self.game.as_mut().unwrap();
+            let alive = game.alive();
+            game.current = alive.iter().cloned()
+                .find(|&index| index > game.current)
+                .or_else(|| alive.first().cloned())
+                .unwrap_or(game.current);
+        }
+        self.begin_turn(output);
+    }
+
+    // Returns true if the game has ended.
+    fn check_finish(&mut self, output: &mut Vec<String>) -> bool {
+        let winner = {
+            let game = self.game.as_ref().unwrap();
+            let alive = game.alive();
+            if alive.len() > 1 {
+                return false;
+            }
+            alive.first().map(|&index| game.order[index].0.clone())
+        };
+        if let Some(winner) = winner {
+            let members = self.members();
+            send(output, &members, ServerMessage::GameFinish { winner });
+        }
+        self.game = None;
+        for player in &mut self.players {
+            player.ready = false;
+        }
+        true
+    }
+
+    fn set_selected(&mut self, output: &mut Vec<String>, selected: Option<Vec2<usize>>) {
+        let members = self.members();
+        let game = self.game.as_mut().unwrap();
+        game.phase = Phase::Attack { selected };
+        let nicks = [game.current_nick().to_owned()];
+        match selected {
+            Some(pos) => {
+                send(output, &members, ServerMessage::SelectCell { row: pos.x, col: pos.y });
+                send(output, &nicks, ServerMessage::CanMove { cells: game.can_move(pos) });
+            }
+            None => {
+                send(output, &members, ServerMessage::DeselectCell);
+                send(output, &nicks, ServerMessage::CanMove { cells: Vec::new() });
+            }
+        }
+    }
+
+    fn click(&mut self, output: &mut Vec<String>, pos: Vec2<usize>) {
+        let (selected, color) = match self.game.as_ref().unwrap().phase {
+            Phase::Attack { selected } => (selected, self.game.as_ref().unwrap().current_color()),
+            Phase::Upgrade { .. } => {
+                self.upgrade(output, pos, 1);
+                return;
+            }
+        };
+        if selected == Some(pos) {
+            self.set_selected(output, None);
+        } else if self.game.as_ref().unwrap().owned_by(pos, color).map_or(false, |count| count >= 2) {
+            self.set_selected(output, Some(pos));
+        } else if let Some(from) = selected {
+            if self.game.as_ref().unwrap().can_move(from).contains(&pos) {
+                self.game.as_mut().unwrap().attack(from, pos);
+                self.set_selected(output, None);
+                self.send_map(output);
+                self.check_finish(output);
+            }
+        }
+    }
+
+    fn upgrade(&mut self, output: &mut Vec<String>, pos: Vec2<usize>, amount: usize) {
+        let members = self.members();
+        let game = self.game.as_mut().unwrap();
+        let energy = match game.phase {
+            Phase::Upgrade { energy, .. } => energy,
+            Phase::Attack { .. } => return,
+        };
+        let color = game.current_color();
+        let count = match game.owned_by(pos, color) {
+            Some(count) => count,
+            None => return,
+        };
+        let added = std::cmp::min(amount, std::cmp::min(energy, MAX_TROLLS.saturating_sub(count)));
+        if added == 0 {
+            return;
+        }
+        game.set_cell(pos, GameCell::Populated { count: count + added, owner: color });
+        let pending = match game.phase {
+            Phase::Upgrade { ref mut energy, ref mut upgrades } => {
+                *energy -= added;
+                upgrades.push((pos, added));
+                upgrades.len()
+            }
+            Phase::Attack { .. } => unreachable!(),
+        };
+        send(output, &members, ServerMessage::EnergyLeft(energy - added));
+        send(output, &members, ServerMessage::PendingUpgrades(pending));
+        self.send_map(output);
+    }
+
+    // Takes back the last upgrade made this phase, or all of them, giving back the energy spent.
+    fn undo(&mut self, output: &mut Vec<String>, all: bool) {
+        let members = self.members();
+        let game = self.game.as_mut().unwrap();
+        let undone = match game.phase {
+            Phase::Upgrade { ref mut upgrades, .. } => {
+                let keep = if all { 0 } else { upgrades.len().saturating_sub(1) };
+                upgrades.split_off(keep)
+            }
+            Phase::Attack { .. } => return,
+        };
+        if undone.is_empty() {
+            return;
+        }
+        let color = game.current_color();
+        let mut restored = 0;
+        for (pos, added) in undone.into_iter().rev() {
+            if let Some(count) = game.owned_by(pos, color) {
+                game.set_cell(pos, GameCell::Populated { count: count - added, owner: color });
+                restored += added;
+            }
+        }
+        let (energy, pending) = match game.phase {
+            Phase::Upgrade { ref mut energy, ref upgrades } => {
+                *energy += restored;
+                (*energy, upgrades.len())
+            }
+            Phase::Attack { .. } => unreachable!(),
+        };
+        send(output, &members, ServerMessage::EnergyLeft(energy));
+        send(output, &members, ServerMessage::PendingUpgrades(pending));
+        self.send_map(output);
+    }
+
+    fn next_phase(&mut self, output: &mut Vec<String>) {
+        let energy = match self.game.as_ref().unwrap().phase {
+            Phase::Attack { selected } => {
+                if selected.is_some() {
+                    self.set_selected(output, None);
+                }
+                let game = self.game.as_ref().unwrap();
+                game.cell_count(game.current_color())
+            }
+            Phase::Upgrade { .. } => {
+                self.next_turn(output);
+                return;
+            }
+        };
+        let members = self.members();
+        self.game.as_mut().unwrap().phase = Phase::Upgrade { energy, upgrades: Vec::new() };
+        send(output, &members, ServerMessage::UpgradePhase);
+        send(output, &members, ServerMessage::EnergyLeft(energy));
+    }
+
+    fn remove(&mut self, output: &mut Vec<String>, nick: &str) {
+        let members = self.members();
+        send(output, &members, ServerMessage::GameLeft { nick: nick.to_owned() });
+        self.players.retain(|player| player.nick != nick);
+        self.spectators.retain(|spectator| spectator != nick);
+        let was_current = match self.game {
+            Some(ref mut game) => {
+                match game.order.iter().position(|&(ref player, _)| player == nick) {
+                    Some(index) => {
+                        let color = game.order[index].1;
+                        game.remove_player(color);
+                        index == game.current
+                    }
+                    None => return,
+                }
+            }
+            None => {
+                self.try_start(output);
+                return;
+            }
+        };
+        self.send_map(output);
+        if !self.check_finish(output) && was_current {
+            self.next_turn(output);
+        }
+    }
+}
+
+fn room_of<'a>(nicks: &BTreeMap<String, Option<String>>, rooms: &'a mut BTreeMap<String, Room>, nick: &str) -> Option<&'a mut Room> {
+    match nicks.get(nick) {
+        Some(&Some(ref name)) => rooms.get_mut(name),
+        _ => None,
+    }
+}
+
+fn current_turn_room<'a>(nicks: &BTreeMap<String, Option<String>>, rooms: &'a mut BTreeMap<String, Room>, nick: &str) -> Option<&'a mut Room> {
+    room_of(nicks, rooms, nick).and_then(|room| {
+        if room.game.as_ref().map_or(false, |game| game.current_nick() == nick) {
+            Some(room)
+        } else {
+            None
+        }
+    })
+}
+
+pub struct GameInfo {
+    pub name: String,
+    pub players: Vec<String>,
+    pub spectators: Vec<String>,
+    // "waiting" before the game starts, "attack" or "upgrade" during it.
+    pub phase: &'static str,
+    pub turn: Option<String>,
+}
+
+pub struct Backend {
+    maps: BTreeMap<String, GameMap>,
+    // Default turn time in seconds, 0 for no limit.
+    turn_time: u64,
+    nicks: BTreeMap<String, Option<String>>,
+    rooms: BTreeMap<String, Room>,
+    output: Vec<String>,
+}
+
+impl Backend {
+    pub fn new(maps: BTreeMap<String, GameMap>, turn_time: u64) -> Self {
+        Self {
+            maps,
+            turn_time,
+            nicks: BTreeMap::new(),
+            rooms: BTreeMap::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // Handles a message from the given nick and returns
+    // the produced "nick1,nick2:message" lines.
+    pub fn handle(&mut self, nick: &str, message: ClientMessage) -> Vec<String> {
+        if let ClientMessage::Login { .. } = message {
+            self.nicks.insert(nick.to_owned(), None);
+        } else if self.nicks.contains_key(nick) {
+            self.command(nick, message);
+        } else {
+            eprintln!("Command from unknown nick {:?}", nick);
+        }
+        mem::replace(&mut self.output, Vec::new())
+    }
+
+    // Ends the turns whose time is up, returns the produced lines like handle does.
+    pub fn tick(&mut self) -> Vec<String> {
+        for (name, room) in &mut self.rooms {
+            if room.is_turn_expired() {
+                eprintln!("Turn time is up in game {:?}", name);
+                room.expire_turn(&mut self.output);
+            }
+        }
+        mem::replace(&mut self.output, Vec::new())
+    }
+
+    pub fn game_of(&self, nick: &str) -> Option<&str> {
+        match self.nicks.get(nick) {
+            Some(&Some(ref name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    // Players and spectators of a room, empty if there is no such room.
+    pub fn members(&self, name: &str) -> Vec<String> {
+        self.rooms.get(name).map_or(Vec::new(), |room| room.members())
+    }
+
+    pub fn is_playing(&self, name: &str) -> bool {
+        self.rooms.get(name).map_or(false, |room| room.game.is_some())
+    }
+
+    pub fn games(&self) -> Vec<GameInfo> {
+        self.rooms.iter().map(|(name, room)| GameInfo {
+            name: name.clone(),
+            players: room.players.iter().map(|player| player.nick.clone()).collect(),
+            spectators: room.spectators.clone(),
+            phase: match room.game {
+                None => "waiting",
+                Some(Match { phase: Phase::Attack { .. }, .. }) => "attack",
+                Some(Match { phase: Phase::Upgrade { .. }, .. }) => "upgrade",
+            },
+            turn: room.game.as_ref().map(|game| game.current_nick().to_owned()),
+        }).collect()
+    }
+
+    // Sends everyone in the game back to the lobby, which removes the game.
+    // The match is dropped first so nobody is declared the winner on the way.
+    pub fn close_game(&mut self, name: &str) -> Vec<String> {
+        let members = match self.rooms.get_mut(name) {
+            Some(room) => {
+                room.game = None;
+                room.members()
+            }
+            None => Vec::new(),
+        };
+        for nick in members {
+            self.leave_game(&nick);
+        }
+        mem::replace(&mut self.output, Vec::new())
+    }
+
+    fn command(&mut self, nick: &str, message: ClientMessage) {
+        use ClientMessage::*;
+        match message {
+            Hello { .. } | Login { .. } | Authenticate { .. } | Register { .. } => {}
+            Resume { .. } => self.resync(nick),
+            Logout => {
+                self.leave_game(nick);
+                self.nicks.remove(nick);
+            }
+            ListGames => {
+                let nicks = [nick.to_owned()];
+                for (name, room) in &self.rooms {
+                    send(&mut self.output, &nicks, ServerMessage::GameList {
+                        name: name.clone(),
+                        player_count: room.players.len(),
+                    });
+                }
+            }
+            ListMaps => {
+                let nicks = [nick.to_owned()];
+                for map in self.maps.values() {
+                    send(&mut self.output, &nicks, ServerMessage::MapInfo {
+                        name: map.info.name.clone(),
+                        players: map.info.players,
+                        author: map.info.author.clone(),
+                    });
+                }
+            }
+            CreateGame { name, map, turn_time } => {
+                if !self.rooms.contains_key(&name) {
+                    let map = match map {
+                        MapChoice::Generated { seed, size, density } => RoomMap::Generated { seed, size, density },
+                        MapChoice::Named(map) => RoomMap::Fixed(self.map(&map)),
+                        MapChoice::Default => RoomMap::Fixed(self.map(DEFAULT_MAP)),
+                    };
+                    let turn_time = match turn_time.unwrap_or(self.turn_time) {
+                        0 => None,
+                        seconds => Some(std::time::Duration::from_secs(seconds)),
+                    };
+                    self.rooms.insert(name.clone(), Room::new(map, turn_time));
+                }
+                self.join_game(nick, &name, PlayType::Player);
+            }
+            JoinGame { name, typ } => self.join_game(nick, &name, typ),
+            LeaveGame => self.leave_game(nick),
+            Ready => self.set_ready(nick, true),
+            Unready => self.set_ready(nick, false),
+            SelectColor { color } => self.select_color(nick, color),
+            HoverCell { row, col } => self.hover(nick, Some(vec2(row, col))),
+            HoverNone => self.hover(nick, None),
+            Chat { scope, text } => self.chat(nick, scope, &text),
+            NextPhase => {
+                if let Some(room) = current_turn_room(&self.nicks, &mut self.rooms, nick) {
+                    room.next_phase(&mut self.output);
+                }
+            }
+            FullUp { row, col } => {
+                if let Some(room) = current_turn_room(&self.nicks, &mut self.rooms, nick) {
+                    room.upgrade(&mut self.output, vec2(row, col), MAX_TROLLS);
+                }
+            }
+            Undo => {
+                if let Some(room) = current_turn_room(&self.nicks, &mut self.rooms, nick) {
+                    room.undo(&mut self.output, false);
+                }
+            }
+            UndoAll => {
+                if let Some(room) = current_turn_room(&self.nicks, &mut self.rooms, nick) {
+                    room.undo(&mut self.output, true);
+                }
+            }
+            Cell { row, col } => {
+                if let Some(room) = current_turn_room(&self.nicks, &mut self.rooms, nick) {
+                    room.click(&mut self.output, vec2(row, col));
+                }
+            }
+        }
+    }
+
+    fn map(&self, name: &str) -> GameMap {
+        match self.maps.get(name).or_else(|| self.maps.get(DEFAULT_MAP)) {
+            Some(map) => map.clone(),
+            None => GameMap::builtin(),
+        }
+    }
+
+    fn join_game(&mut self, nick: &str, name: &str, typ: PlayType) {
+        match self.rooms.get(name) {
+            Some(room) => {
+                let is_player = room.players.iter().any(|player| player.nick == nick);
+                let is_spectator = room.spectators.iter().any(|spectator| spectator == nick);
+                if (typ == PlayType::Player && is_player) || (typ == PlayType::Spectator && is_spectator) {
+                    return;
+                }
+            }
+            None => return,
+        }
+        self.leave_game(nick);
+        let output = &mut self.output;
+        // Leaving may have removed the room if nick was its last member.
+        let room = match self.rooms.get_mut(name) {
+            Some(room) => room,
+            None => return,
+        };
+        let nicks = [nick.to_owned()];
+        let as_player = match typ {
+            PlayType::Player => room.game.is_none() && !room.is_full(),
+            PlayType::Spectator => false,
+        };
+        if as_player {
+            let color = room.free_color().unwrap();
+            send(output, &nicks, ServerMessage::GameEntered { name: name.to_owned(), typ: PlayType::Player });
+            for player in &room.players {
+                send(output, &nicks, ServerMessage::ReadyStatus { nick: player.nick.clone(), ready: player.ready });
+                send(output, &nicks, ServerMessage::PlayerColor { nick: player.nick.clone(), color: player.color });
+            }
+            room.players.push(Player {
+                nick: nick.to_owned(),
+                color,
+                ready: false,
+            });
+            let members = room.members();
+            send(output, &members, ServerMessage::ReadyStatus { nick: nick.to_owned(), ready: false });
+            send(output, &members, ServerMessage::PlayerColor { nick: nick.to_owned(), color });
+        } else {
+            send(output, &nicks, ServerMessage::GameEntered { name: name.to_owned(), typ: PlayType::Spectator });
+            for player in &room.players {
+                send(output, &nicks, ServerMessage::ReadyStatus { nick: player.nick.clone(), ready: player.ready });
+                send(output, &nicks, ServerMessage::PlayerColor { nick: player.nick.clone(), color: player.color });
+            }
+            room.spectators.push(nick.to_owned());
+            let members = room.members();
+            send(output, &members, ServerMessage::SpectatorJoin { nick: nick.to_owned() });
+            room.send_state_to(output, nick);
+        }
+        self.nicks.insert(nick.to_owned(), Some(name.to_owned()));
+    }
+
+    // Sends everything a reconnected client needs to get back into its game.
+    fn resync(&mut self, nick: &str) {
+        let output = &mut self.output;
+        let name = match self.nicks.get(nick) {
+            Some(&Some(ref name)) => name.clone(),
+            _ => return,
+        };
+        let room = self.rooms.get(&name).unwrap();
+        let nicks = [nick.to_owned()];
+        let typ = if room.players.iter().any(|player| player.nick == nick) {
+            PlayType::Player
+        } else {
+            PlayType::Spectator
+        };
+        send(output, &nicks, ServerMessage::GameEntered { name, typ });
+        for player in &room.players {
+            send(output, &nicks, ServerMessage::ReadyStatus { nick: player.nick.clone(), ready: player.ready });
+            send(output, &nicks, ServerMessage::PlayerColor { nick: player.nick.clone(), color: player.color });
+        }
+        room.send_state_to(output, nick);
+        if let Some(ref game) = room.game {
+            if let Phase::Attack { selected: Some(pos) } = game.phase {
+                if game.current_nick() == nick {
+                    send(output, &nicks, ServerMessage::CanMove { cells: game.can_move(pos) });
+                }
+            }
+        }
+    }
+
+    fn leave_game(&mut self, nick: &str) {
+        let name = match self.nicks.get_mut(nick).and_then(|game| game.take()) {
+            Some(name) => name,
+            None => return,
+        };
+        let empty = {
+            let room = self.rooms.get_mut(&name).unwrap();
+            room.remove(&mut self.output, nick);
+            room.is_empty()
+        };
+        if empty {
+            self.rooms.remove(&name);
+        }
+    }
+
+    fn set_ready(&mut self, nick: &str, ready: bool) {
+        let output = &mut self.output;
+        if let Some(room) = room_of(&self.nicks, &mut self.rooms, nick) {
+            if room.game.is_some() {
+                return;
+            }
+            let members = room.members();
+            if let Some(player) = room.players.iter_mut().find(|player| player.nick == nick) {
+                player.ready = ready;
+                send(output, &members, ServerMessage::ReadyStatus { nick: nick.to_owned(), ready });
+            }
+            room.try_start(output);
+        }
+    }
+
+    fn select_color(&mut self, nick: &str, color: char) {
+        if color < 'A' || color >= (b'A' + MAX_PLAYERS as u8) as char {
+            return;
+        }
+        let output = &mut self.output;
+        if let Some(room) = room_of(&self.nicks, &mut self.rooms, nick) {
+            if room.game.is_some() || room.players.iter().any(|player| player.color == color) {
+                return;
+            }
+            let members = room.members();
+            if let Some(player) = room.players.iter_mut().find(|player| player.nick == nick) {
+                player.color = color;
+                send(output, &members, ServerMessage::PlayerColor { nick: nick.to_owned(), color });
+            }
+        }
+    }
+
+    fn hover(&mut self, nick: &str, cell: Option<Vec2<usize>>) {
+        let output = &mut self.output;
+        if let Some(room) = room_of(&self.nicks, &mut self.rooms, nick) {
+            let others: Vec<String> = room.members().into_iter().filter(|member| member != nick).collect();
+            let nick = nick.to_owned();
+            send(output, &others, match cell {
+                Some(pos) => ServerMessage::HoverCell { nick, row: pos.x, col: pos.y },
+                None => ServerMessage::HoverNone { nick },
+            });
+        }
+    }
+
+    fn chat(&mut self, nick: &str, scope: ChatScope, text: &str) {
+        let text: String = text.chars().filter(|c| !c.is_control()).take(MAX_CHAT_LENGTH).collect();
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        let recipients: Vec<String> = {
+            let room = match self.nicks.get(nick) {
+                Some(game) => game.as_ref().and_then(|name| self.rooms.get(name)),
+                None => return,
+            };
+            match (scope, room) {
+                (ChatScope::Lobby, None) => {
+                    self.nicks.iter().filter(|&(_, game)| game.is_none()).map(|(nick, _)| nick.clone()).collect()
+                }
+                (ChatScope::Game, Some(room)) => room.members(),
+                (ChatScope::Spectators, Some(room)) if room.spectators.iter().any(|spectator| spectator == nick) => {
+                    room.spectators.clone()
+                }
+                _ => return,
+            }
+        };
+        send(&mut self.output, &recipients, ServerMessage::Chat {
+            scope,
+            nick: nick.to_owned(),
+            text: text.to_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Row 0 has cells at columns 0, 2 and 4, row 1 at columns 1 and 3.
+    const TINY: &str = "name Tiny\nplayers 2\n3A|__|##|__|1B\n__|##|__|##|__\n";
+    const CLASH: &str = "name Clash\nplayers 2\n4A|__|2B\n";
+
+    fn backend() -> Backend {
+        let mut maps = BTreeMap::new();
+        for text in &[TINY, CLASH] {
+            let map = GameMap::parse(text).unwrap();
+            maps.insert(map.info.name.clone(), map);
+        }
+        Backend::new(maps, 0)
+    }
+
+    fn login(backend: &mut Backend, nick: &str) {
+        backend.handle(nick, ClientMessage::Login { nick: nick.to_owned() });
+    }
+
+    fn create(backend: &mut Backend, nick: &str, map: &str) -> Vec<String> {
+        backend.handle(nick, ClientMessage::CreateGame {
+            name: String::from("g"),
+            map: MapChoice::Named(map.to_owned()),
+            turn_time: None,
+        })
+    }
+
+    fn join(backend: &mut Backend, nick: &str, typ: PlayType) -> Vec<String> {
+        backend.handle(nick, ClientMessage::JoinGame { name: String::from("g"), typ })
+    }
+
+    fn start(map: &str) -> Backend {
+        let mut backend = backend();
+        login(&mut backend, "a");
+        login(&mut backend, "b");
+        create(&mut backend, "a", map);
+        join(&mut backend, "b", PlayType::Player);
+        backend.handle("a", ClientMessage::Ready);
+        backend.handle("b", ClientMessage::Ready);
+        assert!(backend.is_playing("g"));
+        backend
+    }
+
+    fn cell(backend: &Backend, row: usize, col: usize) -> Option<GameCell> {
+        backend.rooms["g"].game.as_ref().unwrap().map[row][col]
+    }
+
+    fn populated(count: usize, owner: char) -> Option<GameCell> {
+        Some(GameCell::Populated { count, owner })
+    }
+
+    fn contains(output: &[String], message: ServerMessage) -> bool {
+        let wire = message.to_wire();
+        output.iter().any(|line| line.splitn(2, ':').nth(1) == Some(wire.as_str()))
+    }
+
+    #[test]
+    fn join_and_leave() {
+        let mut backend = backend();
+        login(&mut backend, "a");
+        login(&mut backend, "b");
+        create(&mut backend, "a", "Tiny");
+        let output = join(&mut backend, "b", PlayType::Player);
+        assert!(contains(&output, ServerMessage::GameEntered { name: String::from("g"), typ: PlayType::Player }));
+        assert_eq!(backend.game_of("b"), Some("g"));
+        assert_eq!(backend.rooms["g"].players.len(), 2);
+
+        backend.handle("b", ClientMessage::LeaveGame);
+        assert_eq!(backend.game_of("b"), None);
+        assert_eq!(backend.rooms["g"].players.len(), 1);
+
+        backend.handle("a", ClientMessage::LeaveGame);
+        assert!(backend.rooms.is_empty());
+    }
+
+    #[test]
+    fn joining_own_room_again() {
+        let mut backend = backend();
+        login(&mut backend, "a");
+        create(&mut backend, "a", "Tiny");
+        assert!(join(&mut backend, "a", PlayType::Player).is_empty());
+        assert!(create(&mut backend, "a", "Tiny").is_empty());
+        assert_eq!(backend.game_of("a"), Some("g"));
+        assert_eq!(backend.rooms["g"].players.len(), 1);
+
+        // Switching to spectator leaves the room first, which removes it.
+        join(&mut backend, "a", PlayType::Spectator);
+        assert_eq!(backend.game_of("a"), None);
+        assert!(backend.rooms.is_empty());
+    }
+
+    #[test]
+    fn spectator_becomes_player() {
+        let mut backend = backend();
+        login(&mut backend, "a");
+        login(&mut backend, "b");
+        create(&mut backend, "a", "Tiny");
+        join(&mut backend, "b", PlayType::Spectator);
+        assert_eq!(backend.rooms["g"].spectators, vec![String::from("b")]);
+        join(&mut backend, "b", PlayType::Player);
+        assert!(backend.rooms["g"].spectators.is_empty());
+        assert_eq!(backend.rooms["g"].players.len(), 2);
+    }
+
+    #[test]
+    fn attack_empty_cell() {
+        let mut backend = start("Tiny");
+        backend.handle("a", ClientMessage::Cell { row: 0, col: 0 });
+        let output = backend.handle("a", ClientMessage::Cell { row: 0, col: 2 });
+        assert!(contains(&output, ServerMessage::DeselectCell));
+        assert_eq!(cell(&backend, 0, 0), populated(1, 'A'));
+        assert_eq!(cell(&backend, 0, 2), populated(2, 'A'));
+    }
+
+    #[test]
+    fn attack_out_of_reach() {
+        let mut backend = start("Tiny");
+        backend.handle("a", ClientMessage::Cell { row: 0, col: 0 });
+        backend.handle("a", ClientMessage::Cell { row: 0, col: 4 });
+        assert_eq!(cell(&backend, 0, 0), populated(3, 'A'));
+        assert_eq!(cell(&backend, 0, 4), populated(1, 'B'));
+    }
+
+    #[test]
+    fn attack_wins_the_game() {
+        let mut backend = start("Clash");
+        backend.handle("a", ClientMessage::Cell { row: 0, col: 0 });
+        let output = backend.handle("a", ClientMessage::Cell { row: 0, col: 2 });
+        assert!(contains(&output, ServerMessage::GameFinish { winner: String::from("a") }));
+        assert!(!backend.is_playing("g"));
+    }
+
+    #[test]
+    fn only_current_player_moves() {
+        let mut backend = start("Tiny");
+        assert!(backend.handle("b", ClientMessage::Cell { row: 0, col: 4 }).is_empty());
+        assert!(backend.handle("b", ClientMessage::NextPhase).is_empty());
+    }
+
+    #[test]
+    fn upgrade_and_undo() {
+        let mut backend = start("Tiny");
+        let output = backend.handle("a", ClientMessage::NextPhase);
+        assert!(contains(&output, ServerMessage::EnergyLeft(1)));
+
+        let output = backend.handle("a", ClientMessage::Cell { row: 0, col: 0 });
+        assert!(contains(&output, ServerMessage::EnergyLeft(0)));
+        assert!(contains(&output, ServerMessage::PendingUpgrades(1)));
+        assert_eq!(cell(&backend, 0, 0), populated(4, 'A'));
+
+        let output = backend.handle("a", ClientMessage::Undo);
+        assert!(contains(&output, ServerMessage::EnergyLeft(1)));
+        assert!(contains(&output, ServerMessage::PendingUpgrades(0)));
+        assert_eq!(cell(&backend, 0, 0), populated(3, 'A'));
+
+        backend.handle("a", ClientMessage::FullUp { row: 0, col: 0 });
+        assert_eq!(cell(&backend, 0, 0), populated(4, 'A'));
+        backend.handle("a", ClientMessage::UndoAll);
+        assert_eq!(cell(&backend, 0, 0), populated(3, 'A'));
+
+        // Upgrades can only go to own cells.
+        assert!(backend.handle("a", ClientMessage::Cell { row: 0, col: 4 }).is_empty());
+
+        let output = backend.handle("a", ClientMessage::NextPhase);
+        assert!(contains(&output, ServerMessage::Turn { nick: String::from("b"), time_left: None }));
+    }
+
+    // The server lets a session be resumed before it noticed the old connection drop,
+    // so the player is still in the game and only needs the state sent again.
+    #[test]
+    fn resume_while_in_game() {
+        let mut backend = start("Tiny");
+        let output = backend.handle("a", ClientMessage::Resume { nick: String::from("a"), token: String::from("token") });
+        assert!(contains(&output, ServerMessage::GameEntered { name: String::from("g"), typ: PlayType::Player }));
+        assert!(contains(&output, ServerMessage::GameStart));
+        assert!(output.iter().all(|line| line.starts_with("a:")));
+        assert_eq!(backend.game_of("a"), Some("g"));
+        assert_eq!(backend.rooms["g"].players.len(), 2);
+    }
+
+    // Only lines sent to all members are recorded for replays, so the state
+    // sent to a spectator joining mid-game must go to the spectator alone.
+    #[test]
+    fn spectator_gets_the_state_alone() {
+        let mut backend = start("Tiny");
+        login(&mut backend, "c");
+        let output = join(&mut backend, "c", PlayType::Spectator);
+        assert!(contains(&output, ServerMessage::GameStart));
+        let members = backend.members("g");
+        assert_eq!(members.len(), 3);
+        for line in &output {
+            let message = ServerMessage::parse(line.splitn(2, ':').nth(1).unwrap()).unwrap();
+            if message == ServerMessage::GameStart {
+                assert!(line.starts_with("c:"));
+            }
+        }
+    }
+}
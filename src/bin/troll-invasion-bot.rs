@@ -0,0 +1,295 @@
+// Headless client that joins a game and plays it without a window.
+
+#[cfg(not(target_os = "emscripten"))]
+extern crate ws;
+#[cfg(not(target_os = "emscripten"))]
+extern crate argparse;
+#[cfg(not(target_os = "emscripten"))]
+extern crate codevisual;
+
+#[cfg(not(target_os = "emscripten"))]
+pub(crate) use codevisual::prelude::*;
+
+#[cfg(not(target_os = "emscripten"))]
+#[path = "../model.rs"]
+#[allow(dead_code)]
+mod model;
+#[cfg(not(target_os = "emscripten"))]
+#[path = "../connection.rs"]
+#[allow(dead_code)]
+mod connection;
+
+#[cfg(not(target_os = "emscripten"))]
+pub(crate) use model::*;
+
+#[cfg(not(target_os = "emscripten"))]
+enum Phase {
+    Attack {
+        selected: Option<Vec2<usize>>,
+        can_move: Vec<Vec2<usize>>,
+    },
+    Upgrade {
+        energy: usize,
+    },
+}
+
+#[cfg(not(target_os = "emscripten"))]
+struct Bot {
+    nick: String,
+    game: String,
+    sender: connection::Sender,
+    spectating: bool,
+    color: Option<char>,
+    map: Vec<Vec<Option<GameCell>>>,
+    my_turn: bool,
+    waiting: bool,
+    phase: Phase,
+}
+
+#[cfg(not(target_os = "emscripten"))]
+impl Bot {
+    fn cell(&self, pos: Vec2<usize>) -> Option<GameCell> {
+        self.map.get(pos.x).and_then(|line| line.get(pos.y)).and_then(|cell| *cell)
+    }
+
+    fn own_cells(&self) -> Vec<(Vec2<usize>, usize)> {
+        let color = match self.color {
+            Some(color) => color,
+            None => return Vec::new(),
+        };
+        let mut result = Vec::new();
+        for (i, line) in self.map.iter().enumerate() {
+            for (j, cell) in line.iter().enumerate() {
+                if let Some(GameCell::Populated { count, owner }) = *cell {
+                    if owner == color {
+                        result.push((vec2(i, j), count));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn is_enemy(&self, pos: Vec2<usize>) -> bool {
+        match self.cell(pos) {
+            Some(GameCell::Populated { owner, .. }) => Some(owner) != self.color,
+            _ => false,
+        }
+    }
+
+    // How much taking the cell with the given number of attackers is worth, if possible at all.
+    fn attack_score(&self, attackers: usize, target: Vec2<usize>) -> Option<usize> {
+        match self.cell(target) {
+            Some(GameCell::Empty) => Some(1),
+            Some(GameCell::Populated { count, owner }) if Some(owner) != self.color => {
+                if attackers > count {
+                    Some(2 + attackers - count)
+                } else if attackers + 1 >= count {
+                    // Leaves the defender with a single troll.
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn best_attack(&self) -> Option<(Vec2<usize>, Vec2<usize>)> {
+        let mut best = None;
+        for (from, count) in self.own_cells() {
+            if count < 2 {
+                continue;
+            }
+            for to in neighbours(from) {
+                if let Some(score) = self.attack_score(count - 1, to) {
+                    if best.map_or(true, |(best_score, _, _)| score > best_score) {
+                        best = Some((score, from, to));
+                    }
+                }
+            }
+        }
+        best.map(|(_, from, to)| (from, to))
+    }
+
+    // Reinforces the weakest cell on the front line, or any cell if there is no front.
+    fn best_upgrade(&self) -> Option<Vec2<usize>> {
+        self.own_cells().into_iter()
+            .filter(|&(_, count)| count < MAX_TROLLS)
+            .min_by_key(|&(pos, count)| {
+                let front = neighbours(pos).into_iter().any(|next| self.is_enemy(next));
+                (!front, count)
+            })
+            .map(|(pos, _)| pos)
+    }
+
+    fn act(&mut self) {
+        if !self.my_turn || self.waiting {
+            return;
+        }
+        let message = match self.phase {
+            Phase::Attack { selected: Some(from), ref can_move } => {
+                let count = match self.cell(from) {
+                    Some(GameCell::Populated { count, .. }) => count,
+                    _ => 0,
+                };
+                let target = can_move.iter().cloned()
+                    .filter_map(|to| self.attack_score(count.saturating_sub(1), to).map(|score| (score, to)))
+                    .max_by_key(|&(score, _)| score);
+                match target {
+                    Some((_, to)) => ClientMessage::Cell { row: to.x, col: to.y },
+                    None => ClientMessage::Cell { row: from.x, col: from.y },
+                }
+            }
+            Phase::Attack { selected: None, .. } => match self.best_attack() {
+                Some((from, _)) => ClientMessage::Cell { row: from.x, col: from.y },
+                None => ClientMessage::NextPhase,
+            },
+            Phase::Upgrade { energy } => match self.best_upgrade() {
+                Some(pos) if energy > 0 => ClientMessage::Cell { row: pos.x, col: pos.y },
+                _ => ClientMessage::NextPhase,
+            },
+        };
+        // Every action is answered, so don't act again until it is.
+        self.waiting = true;
+        self.sender.send(message);
+    }
+
+    fn handle(&mut self, message: ServerMessage) {
+        use ServerMessage::*;
+        match message {
+            Welcome { .. } => {
+                self.sender.send(ClientMessage::CreateGame {
+                    name: self.game.clone(),
+                    map: MapChoice::Default,
+                    turn_time: None,
+                });
+            }
+            Rejected { reason } => {
+                eprintln!("Rejected by server: {}", reason.description());
+                std::process::exit(1);
+            }
+            GameEntered { typ, .. } => {
+                self.spectating = typ == PlayType::Spectator;
+                if self.spectating {
+                    eprintln!("Game {:?} is already running, waiting for it to finish", self.game);
+                } else {
+                    self.sender.send(ClientMessage::Ready);
+                }
+            }
+            PlayerColor { nick, color } => {
+                if nick == self.nick {
+                    self.color = Some(color);
+                }
+            }
+            GameStart => {
+                self.map.clear();
+                self.my_turn = false;
+            }
+            MapLine(index, line) => {
+                if self.map.len() <= index {
+                    self.map.resize(index + 1, Vec::new());
+                }
+                self.map[index] = line;
+            }
+            Turn { nick, .. } => {
+                self.my_turn = nick == self.nick;
+                self.waiting = false;
+                self.phase = Phase::Attack { selected: None, can_move: Vec::new() };
+            }
+            SelectCell { row, col } => {
+                self.waiting = false;
+                self.phase = Phase::Attack { selected: Some(vec2(row, col)), can_move: Vec::new() };
+            }
+            DeselectCell => {
+                self.waiting = false;
+                self.phase = Phase::Attack { selected: None, can_move: Vec::new() };
+            }
+            CanMove { cells } => {
+                if let Phase::Attack { ref mut can_move, .. } = self.phase {
+                    *can_move = cells;
+                }
+            }
+            UpgradePhase => {
+                self.waiting = false;
+                self.phase = Phase::Upgrade { energy: 0 };
+            }
+            EnergyLeft(energy) => {
+                self.waiting = false;
+                self.phase = Phase::Upgrade { energy };
+            }
+            GameFinish { winner } => {
+                eprintln!("{} won", winner);
+                self.my_turn = false;
+                self.map.clear();
+                if self.spectating {
+                    self.sender.send(ClientMessage::JoinGame { name: self.game.clone(), typ: PlayType::Player });
+                } else {
+                    self.sender.send(ClientMessage::Ready);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(target_os = "emscripten")]
+fn main() {}
+
+#[cfg(not(target_os = "emscripten"))]
+fn main() {
+    let mut host = String::from("localhost");
+    let mut port: u16 = 8008;
+    let mut nick = String::from("bot");
+    let mut game = String::from("bots");
+    let mut think_time: u64 = 300;
+    let mut password = None;
+
+    {
+        let mut ap = argparse::ArgumentParser::new();
+        ap.set_description("TrollInvasion bot. Joins a game (creating it if needed) and plays it.");
+        ap.refer(&mut host).add_option(&["-c", "--connect"], argparse::Store, "Host to connect to");
+        ap.refer(&mut port).add_option(&["-p", "--port"], argparse::Store, "Specify port");
+        ap.refer(&mut nick).add_option(&["--nick"], argparse::Store, "Nickname");
+        ap.refer(&mut password).add_option(&["--password"], argparse::StoreOption, "Password of a registered nickname");
+        ap.refer(&mut game).add_option(&["--game"], argparse::Store, "Name of the game to join");
+        ap.refer(&mut think_time).add_option(&["--think"], argparse::Store, "Milliseconds to wait before each action");
+        ap.parse_args_or_exit();
+    }
+
+    let credentials = match password {
+        Some(password) => connection::Credentials::Password(password),
+        None => connection::Credentials::Guest,
+    };
+    let (sender, receiver) = connection::connect(&nick, credentials, &host, port);
+    let mut bot = Bot {
+        nick,
+        game,
+        sender,
+        spectating: false,
+        color: None,
+        map: Vec::new(),
+        my_turn: false,
+        waiting: false,
+        phase: Phase::Attack { selected: None, can_move: Vec::new() },
+    };
+    // Messages of one server response arrive separately, so the bot only
+    // acts once the connection has been quiet for a while.
+    let poll = std::time::Duration::from_millis(20);
+    let think_time = std::time::Duration::from_millis(think_time);
+    let mut last_message = std::time::Instant::now();
+    loop {
+        if receiver.state() == connection::ConnectionState::Failed {
+            eprintln!("Lost connection to the server");
+            std::process::exit(1);
+        }
+        while let Some(message) = receiver.try_recv() {
+            bot.handle(message);
+            last_message = std::time::Instant::now();
+        }
+        if last_message.elapsed() >= think_time {
+            bot.act();
+        }
+        thread::sleep(poll);
+    }
+}
@@ -1,90 +1,365 @@
 use ::*;
 
+const BASE_RETRY_DELAY: u64 = 1;
+const MAX_RETRY_DELAY: u64 = 30;
+const MAX_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    // The connection dropped and is being reestablished right away.
+    Lost,
+    Retrying {
+        attempt: usize,
+        next_attempt: std::time::Instant,
+    },
+    // Gave up, a new connection has to be made.
+    Failed,
+}
+
+// How to log in when there is no session to resume.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Guest,
+    Password(String),
+    // Registers the nick, then logs in with the password from then on.
+    Register(String),
+}
+
+impl Credentials {
+    fn login_message(&self, nick: &str) -> ClientMessage {
+        let nick = nick.to_owned();
+        match *self {
+            Credentials::Guest => ClientMessage::Login { nick },
+            Credentials::Password(ref password) => ClientMessage::Authenticate { nick, password: password.clone() },
+            Credentials::Register(ref password) => ClientMessage::Register { nick, password: password.clone() },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Session {
+    token: String,
+    grace: u64,
+}
+
+// State of the connection, shared with the game through Sender and Receiver.
+struct Shared {
+    state: ConnectionState,
+    attempt: usize,
+    credentials: Credentials,
+    session: Option<Session>,
+    disconnected: Option<std::time::Instant>,
+    // Set once the game is done with the connection, so it is never made again.
+    closed: bool,
+}
+
+impl Shared {
+    fn new(credentials: Credentials) -> Self {
+        Self {
+            state: ConnectionState::Connecting,
+            attempt: 0,
+            credentials,
+            session: None,
+            disconnected: None,
+            closed: false,
+        }
+    }
+
+    fn login_message(&self, nick: &str) -> ClientMessage {
+        match self.session {
+            Some(Session { ref token, .. }) => ClientMessage::Resume { nick: nick.to_owned(), token: token.clone() },
+            None => self.credentials.login_message(nick),
+        }
+    }
+
+    fn connected(&mut self) {
+        self.state = ConnectionState::Connected;
+        self.attempt = 0;
+        self.disconnected = None;
+    }
+
+    fn handle(&mut self, message: &ServerMessage) {
+        match *message {
+            ServerMessage::Session { ref token, grace } => {
+                self.session = Some(Session { token: token.clone(), grace });
+                if let Credentials::Register(password) = self.credentials.clone() {
+                    self.credentials = Credentials::Password(password);
+                }
+            }
+            ServerMessage::Rejected { .. } => {
+                self.session = None;
+                self.state = ConnectionState::Failed;
+            }
+            _ => {}
+        }
+    }
+
+    // Called when the connection is closed or could not be made,
+    // returns how long to wait before the next attempt, if there is one.
+    fn next_attempt(&mut self) -> Option<std::time::Duration> {
+        if self.closed || self.state == ConnectionState::Failed {
+            return None;
+        }
+        if self.state == ConnectionState::Connected {
+            self.disconnected = Some(std::time::Instant::now());
+        }
+        let expired = match (self.session.as_ref(), self.disconnected) {
+            (Some(session), Some(disconnected)) => disconnected.elapsed() >= std::time::Duration::from_secs(session.grace),
+            _ => false,
+        };
+        if expired {
+            eprintln!("Could not resume the session in time");
+            self.state = ConnectionState::Failed;
+            return None;
+        }
+        if self.state == ConnectionState::Connected {
+            self.state = ConnectionState::Lost;
+            return Some(std::time::Duration::from_secs(0));
+        }
+        self.attempt += 1;
+        if self.attempt > MAX_ATTEMPTS {
+            self.state = ConnectionState::Failed;
+            return None;
+        }
+        let delay = std::time::Duration::from_secs(std::cmp::min(BASE_RETRY_DELAY << (self.attempt - 1), MAX_RETRY_DELAY));
+        self.state = ConnectionState::Retrying {
+            attempt: self.attempt,
+            next_attempt: std::time::Instant::now() + delay,
+        };
+        Some(delay)
+    }
+}
+
 #[derive(Clone)]
 pub struct Sender {
     nick: String,
+    shared: Arc<Mutex<Shared>>,
     #[cfg(not(target_os = "emscripten"))]
     connection: Arc<Mutex<Option<ws::Sender>>>,
 }
 
 pub struct Receiver {
     receiver: std::sync::mpsc::Receiver<ServerMessage>,
+    shared: Arc<Mutex<Shared>>,
+    sender: Sender,
+    #[cfg(not(target_os = "emscripten"))]
+    retry: std::sync::mpsc::Sender<()>,
 }
 
-pub fn connect(nick: &str, host: &str, port: u16) -> (Sender, Receiver) {
+pub fn connect(nick: &str, credentials: Credentials, host: &str, port: u16) -> (Sender, Receiver) {
     let nick = nick.to_owned();
     let (sender, receiver) = std::sync::mpsc::channel();
+    let shared = Arc::new(Mutex::new(Shared::new(credentials)));
     #[cfg(target_os = "emscripten")]
         return {
-        let callback = webby::Callback::from(move |addr: i32| {
-            let line = unsafe { std::ffi::CStr::from_ptr(addr as *mut _).to_string_lossy() };
-            println!("input {:?}", line);
-            if let Some(message) = ServerMessage::parse(&line) {
-                sender.send(message).unwrap();
+        fn read_line(addr: i32) -> String {
+            unsafe { std::ffi::CStr::from_ptr(addr as *mut _).to_string_lossy().into_owned() }
+        }
+        let callback = webby::Callback::from({
+            let shared = shared.clone();
+            let nick = nick.clone();
+            move |addr: i32| {
+                let line = read_line(addr);
+                println!("input {:?}", line);
+                match ServerMessage::parse(&line) {
+                    Ok(message) => {
+                        shared.lock().unwrap().handle(&message);
+                        match message {
+                            ServerMessage::Session { ref token, grace } => {
+                                let resume = ClientMessage::Resume { nick: nick.clone(), token: token.clone() }.to_wire();
+                                js! {
+                                    TrollInvasion.setSession(@{&nick}, @{resume}, @{grace as u32});
+                                }
+                            }
+                            ServerMessage::Rejected { .. } => {
+                                js! {
+                                    TrollInvasion.clearSession();
+                                }
+                            }
+                            _ => {}
+                        }
+                        // The receiving end is gone once the game drops the connection.
+                        let _ = sender.send(message);
+                    }
+                    Err(e) => println!("Skipping bad message {:?}: {}", line, e),
+                }
+            }
+        });
+        let events = webby::Callback::from({
+            let shared = shared.clone();
+            let nick = nick.clone();
+            move |addr: i32| {
+                let mut shared = shared.lock().unwrap();
+                match read_line(addr).as_str() {
+                    "open" => shared.connected(),
+                    _ => {
+                        if let Some(delay) = shared.next_attempt() {
+                            let delay = delay.as_secs() as u32 * 1000;
+                            js! {
+                                TrollInvasion.reconnect(@{shared.login_message(&nick).to_wire()}, @{delay});
+                            }
+                        }
+                    }
+                }
             }
         });
+        let login = shared.lock().unwrap().login_message(&nick).to_wire();
         js! {
-            TrollInvasion.connect(@{&*HOST.lock().unwrap()}, @{&*PORT.lock().unwrap()}, @{&nick}, @callback);
+            TrollInvasion.connect(@{host}, @{port as u32},
+                                  @{ClientMessage::hello().to_wire()},
+                                  @{&nick},
+                                  @{login},
+                                  @callback, @events);
         }
-        (Sender { nick }, Receiver { receiver })
+        let sender = Sender { nick, shared: shared.clone() };
+        (sender.clone(), Receiver { receiver, shared, sender })
     };
     #[cfg(not(target_os = "emscripten"))]
         return {
         let connection = Arc::new(Mutex::new(None));
+        let (retry, retry_receiver) = std::sync::mpsc::channel();
         thread::spawn({
             let connection = connection.clone();
+            let shared = shared.clone();
             let nick = nick.clone();
             let host = host.to_owned();
             move || {
                 let address = format!("ws://{}:{}", host, port);
-                eprintln!("Connecting to {}", address);
-                ws::connect(address, |conn| {
-                    struct Handler {
-                        nick: String,
-                        sender: std::sync::mpsc::Sender<ServerMessage>,
-                        connection: Arc<Mutex<Option<ws::Sender>>>,
-                        conn: ws::Sender,
+                loop {
+                    if shared.lock().unwrap().closed {
+                        break;
                     }
-                    impl ws::Handler for Handler {
-                        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
-                            self.conn.send(format!("+{}", self.nick)).unwrap();
-                            *self.connection.lock().unwrap() = Some(self.conn.clone());
-                            Ok(())
-                        }
-                        fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
-                            let message = message.into_text().unwrap();
-                            eprintln!("{}", message);
-                            if let Some(message) = ServerMessage::parse(&message) {
-                                self.sender.send(message).unwrap();
-                            } else {
-                                eprintln!("Message unsupported: {:?}", message);
-                            }
-                            Ok(())
+                    let login = shared.lock().unwrap().login_message(&nick);
+                    eprintln!("Connecting to {}", address);
+                    let result = ws::connect(address.clone(), |conn| {
+                        Handler {
+                            login: login.clone(),
+                            sender: sender.clone(),
+                            connection: connection.clone(),
+                            shared: shared.clone(),
+                            conn,
                         }
+                    });
+                    if let Err(e) = result {
+                        eprintln!("Connection to {} failed: {}", address, e);
                     }
-                    Handler {
-                        nick: nick.clone(),
-                        sender: sender.clone(),
-                        connection: connection.clone(),
-                        conn,
+                    *connection.lock().unwrap() = None;
+                    let delay = match shared.lock().unwrap().next_attempt() {
+                        Some(delay) => delay,
+                        None => break,
+                    };
+                    // Waits for the delay to pass or for a manual retry.
+                    match retry_receiver.recv_timeout(delay) {
+                        Ok(()) | Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
                     }
-                }).unwrap();
+                }
             }
         });
-        (Sender { nick, connection }, Receiver { receiver })
+        let sender = Sender { nick, shared: shared.clone(), connection };
+        (sender.clone(), Receiver { receiver, shared, sender, retry })
     };
 }
 
+#[cfg(not(target_os = "emscripten"))]
+struct Handler {
+    login: ClientMessage,
+    sender: std::sync::mpsc::Sender<ServerMessage>,
+    connection: Arc<Mutex<Option<ws::Sender>>>,
+    shared: Arc<Mutex<Shared>>,
+    conn: ws::Sender,
+}
+
+#[cfg(not(target_os = "emscripten"))]
+impl ws::Handler for Handler {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        // The game may have closed the connection while it was being made.
+        if shared.closed {
+            return self.conn.close(ws::CloseCode::Normal);
+        }
+        self.conn.send(ClientMessage::hello().to_wire())?;
+        self.conn.send(self.login.to_wire())?;
+        *self.connection.lock().unwrap() = Some(self.conn.clone());
+        shared.connected();
+        Ok(())
+    }
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        let message = match message.into_text() {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Skipping non-text message: {}", e);
+                return Ok(());
+            }
+        };
+        eprintln!("{}", message);
+        match ServerMessage::parse(&message) {
+            Ok(message) => {
+                self.shared.lock().unwrap().handle(&message);
+                if let ServerMessage::Session { .. } = message {
+                    return Ok(());
+                }
+                // The receiving end is gone once the game drops the connection.
+                let _ = self.sender.send(message);
+            }
+            Err(e) => eprintln!("Skipping bad message {:?}: {}", message, e),
+        }
+        Ok(())
+    }
+}
+
 impl Sender {
-    pub fn send<S: std::borrow::Borrow<str>>(&mut self, message: S) {
+    // A sender that drops everything, for screens that are not connected to a server.
+    #[cfg(not(target_os = "emscripten"))]
+    pub fn offline(nick: &str) -> Self {
+        Sender {
+            nick: nick.to_owned(),
+            shared: Arc::new(Mutex::new(Shared::new(Credentials::Guest))),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn send(&mut self, message: ClientMessage) {
+        // Logging out ends the session, so it must not be resumed.
+        let logout = message == ClientMessage::Logout;
+        if logout {
+            self.shared.lock().unwrap().session = None;
+        }
+        let message = message.to_wire();
         #[cfg(target_os = "emscripten")]
             js! {
-                TrollInvasion.send(@{message.borrow()});
+                if (@{logout}) {
+                    TrollInvasion.clearSession();
+                }
+                TrollInvasion.send(@{message});
             };
         #[cfg(not(target_os = "emscripten"))]
             {
                 if let Some(connection) = self.connection.lock().unwrap().as_ref() {
-                    connection.send(message.borrow()).unwrap();
+                    if let Err(e) = connection.send(message) {
+                        eprintln!("Failed to send: {}", e);
+                    }
+                }
+            }
+    }
+
+    // Closes the connection for good, it is not made again after that.
+    // Messages sent before are still delivered.
+    pub fn close(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.closed = true;
+        #[cfg(target_os = "emscripten")]
+            js! {
+                TrollInvasion.close();
+            };
+        #[cfg(not(target_os = "emscripten"))]
+            {
+                if let Some(connection) = self.connection.lock().unwrap().take() {
+                    if let Err(e) = connection.close(ws::CloseCode::Normal) {
+                        eprintln!("Failed to close: {}", e);
+                    }
                 }
             }
     }
@@ -97,4 +372,29 @@ impl Receiver {
             _ => None,
         }
     }
-}
\ No newline at end of file
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared.lock().unwrap().state
+    }
+
+    // Credentials to log in with next time, a registration turns into a password login once done.
+    pub fn credentials(&self) -> Credentials {
+        self.shared.lock().unwrap().credentials.clone()
+    }
+
+    pub fn close(&self) {
+        self.sender.close();
+    }
+
+    // Skips the wait before the next reconnect attempt.
+    pub fn retry(&self) {
+        #[cfg(target_os = "emscripten")]
+            js! {
+                TrollInvasion.retryNow();
+            };
+        #[cfg(not(target_os = "emscripten"))]
+            {
+                let _ = self.retry.send(());
+            }
+    }
+}
@@ -0,0 +1,197 @@
+use ::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    Session,
+    Lobby,
+    Game,
+    Hover,
+    Chat,
+}
+
+impl CommandClass {
+    pub fn of(message: &ClientMessage) -> Self {
+        use ClientMessage::*;
+        match *message {
+            Hello { .. } | Login { .. } | Authenticate { .. } | Register { .. } |
+            Resume { .. } | Logout => CommandClass::Session,
+            ListGames | ListMaps | CreateGame { .. } | JoinGame { .. } | LeaveGame |
+            Ready | Unready | SelectColor { .. } => CommandClass::Lobby,
+            HoverCell { .. } | HoverNone => CommandClass::Hover,
+            Chat { .. } => CommandClass::Chat,
+            NextPhase | FullUp { .. } | Undo | UndoAll | Cell { .. } => CommandClass::Game,
+        }
+    }
+}
+
+// Allows `burst` messages at once, refilling at `rate` messages per second.
+#[derive(Debug, Copy, Clone)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+// Per-connection limits. Hovers over the limit are coalesced into the latest one,
+// other messages over the limit are dropped. Every dropped message uses up the
+// flood allowance, and a connection that runs out of it is disconnected.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub session: Limit,
+    pub lobby: Limit,
+    pub game: Limit,
+    pub hover: Limit,
+    pub chat: Limit,
+    pub flood: Limit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            session: Limit { rate: 1.0, burst: 5.0 },
+            lobby: Limit { rate: 2.0, burst: 10.0 },
+            game: Limit { rate: 10.0, burst: 20.0 },
+            hover: Limit { rate: 10.0, burst: 10.0 },
+            chat: Limit { rate: 1.0, burst: 5.0 },
+            flood: Limit { rate: 1.0, burst: 20.0 },
+        }
+    }
+}
+
+impl RateLimits {
+    // Overrides a limit given as "class=rate/burst", e.g. "hover=5/10".
+    pub fn set(&mut self, spec: &str) -> Result<(), String> {
+        let bad = || format!("expected class=rate/burst, got {:?}", spec);
+        let (class, limit) = match spec.find('=') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => return Err(bad()),
+        };
+        let (rate, burst) = match limit.find('/') {
+            Some(index) => (&limit[..index], &limit[index + 1..]),
+            None => return Err(bad()),
+        };
+        let limit = Limit {
+            rate: rate.parse().map_err(|_| bad())?,
+            burst: burst.parse().map_err(|_| bad())?,
+        };
+        if limit.rate <= 0.0 || limit.burst < 1.0 {
+            return Err(format!("limit {:?} would block everything", spec));
+        }
+        match class {
+            "session" => self.session = limit,
+            "lobby" => self.lobby = limit,
+            "game" => self.game = limit,
+            "hover" => self.hover = limit,
+            "chat" => self.chat = limit,
+            "flood" => self.flood = limit,
+            _ => return Err(format!("unknown class {:?}, expected session, lobby, game, hover, chat or flood", class)),
+        }
+        Ok(())
+    }
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: std::time::Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: std::time::Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.updated.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = std::time::Instant::now();
+    }
+
+    fn take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Time until the next token is available.
+    fn wait(&mut self) -> std::time::Duration {
+        self.refill();
+        let seconds = ((1.0 - self.tokens) / self.limit.rate).max(0.0);
+        std::time::Duration::from_millis((seconds * 1000.0).ceil() as u64)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    // Hold on to the hover and send it once the limit allows.
+    Coalesce,
+    // Drop the message, `warn` is set when the connection just started flooding.
+    Drop { warn: bool },
+    Disconnect,
+}
+
+pub struct Limiter {
+    buckets: HashMap<CommandClass, Bucket>,
+    flood: Bucket,
+    throttled: bool,
+    pub received: usize,
+    pub coalesced: usize,
+    pub dropped: usize,
+}
+
+impl Limiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        let mut buckets = HashMap::new();
+        buckets.insert(CommandClass::Session, Bucket::new(limits.session));
+        buckets.insert(CommandClass::Lobby, Bucket::new(limits.lobby));
+        buckets.insert(CommandClass::Game, Bucket::new(limits.game));
+        buckets.insert(CommandClass::Hover, Bucket::new(limits.hover));
+        buckets.insert(CommandClass::Chat, Bucket::new(limits.chat));
+        Self {
+            buckets,
+            flood: Bucket::new(limits.flood),
+            throttled: false,
+            received: 0,
+            coalesced: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn check(&mut self, class: CommandClass) -> Verdict {
+        self.received += 1;
+        if self.buckets.get_mut(&class).unwrap().take() {
+            self.throttled = false;
+            return Verdict::Allow;
+        }
+        if class == CommandClass::Hover {
+            self.coalesced += 1;
+            return Verdict::Coalesce;
+        }
+        self.dropped += 1;
+        if !self.flood.take() {
+            return Verdict::Disconnect;
+        }
+        let warn = !self.throttled;
+        self.throttled = true;
+        Verdict::Drop { warn }
+    }
+
+    // Takes a hover token for a coalesced hover, or tells how long to wait for one.
+    pub fn flush_hover(&mut self) -> Result<(), std::time::Duration> {
+        let bucket = self.buckets.get_mut(&CommandClass::Hover).unwrap();
+        if bucket.take() {
+            Ok(())
+        } else {
+            Err(bucket.wait())
+        }
+    }
+}
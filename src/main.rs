@@ -2,6 +2,8 @@
 extern crate ws;
 #[cfg(not(target_os = "emscripten"))]
 extern crate argparse;
+#[cfg(not(target_os = "emscripten"))]
+extern crate sha2;
 #[macro_use]
 extern crate lazy_static;
 
@@ -17,6 +19,20 @@ pub(crate) use codevisual::prelude::*;
 
 #[cfg(not(target_os = "emscripten"))]
 mod server;
+#[cfg(not(target_os = "emscripten"))]
+mod backend;
+#[cfg(not(target_os = "emscripten"))]
+mod map;
+#[cfg(not(target_os = "emscripten"))]
+mod mapgen;
+#[cfg(not(target_os = "emscripten"))]
+mod replay;
+#[cfg(not(target_os = "emscripten"))]
+mod limits;
+#[cfg(not(target_os = "emscripten"))]
+mod accounts;
+#[cfg(not(target_os = "emscripten"))]
+mod status;
 mod screen;
 mod model;
 mod connection;
@@ -28,7 +44,14 @@ lazy_static! {
     static ref HOST: Mutex<String> = Mutex::new(String::new());
     static ref PORT: Mutex<u16> = Mutex::new(0);
     static ref NICK: Mutex<String> = Mutex::new(String::new());
+    static ref CREDENTIALS: Mutex<connection::Credentials> = Mutex::new(connection::Credentials::Guest);
     static ref RECEIVER: Mutex<Option<connection::Receiver>> = Mutex::new(None);
+    static ref ANIMATION_SPEED: Mutex<f64> = Mutex::new(1.0);
+}
+
+#[cfg(not(target_os = "emscripten"))]
+lazy_static! {
+    static ref REPLAY: Mutex<Option<Vec<(f64, ServerMessage)>>> = Mutex::new(None);
 }
 
 static mut RESOURCES: *const Resources = 0 as _;
@@ -38,7 +61,30 @@ fn resources() -> &'static Resources {
 }
 
 struct TrollInvasion {
+    app: Rc<codevisual::App>,
     screen: Box<screen::Screen>,
+    status: screen::ConnectionStatus,
+    notice: screen::Notice,
+}
+
+impl TrollInvasion {
+    // Typing does nothing while the connection status is shown instead of the screen.
+    fn handle_typed(&mut self, typed: Vec<char>) {
+        match connection_state() {
+            None | Some(connection::ConnectionState::Connected) => {}
+            Some(_) => return,
+        }
+        for c in typed {
+            if let Some(screen) = self.screen.handle(screen::Event::Text(c)) {
+                self.screen = screen;
+            }
+        }
+    }
+}
+
+// State of the current connection, if there is one.
+fn connection_state() -> Option<connection::ConnectionState> {
+    RECEIVER.lock().unwrap().as_ref().map(|receiver| receiver.state())
 }
 
 #[derive(Resources)]
@@ -62,38 +108,116 @@ impl codevisual::Game for TrollInvasion {
         if let Some(screen) = self.screen.handle(screen::Event::Update(delta_time)) {
             self.screen = screen;
         }
-        let receiver = RECEIVER.lock().unwrap();
-        if let Some(ref receiver) = *receiver {
-            while let Some(message) = receiver.try_recv() {
-                if let Some(screen) = self.screen.handle(screen::Event::Message(message)) {
-                    self.screen = screen;
+        self.notice.update(delta_time);
+        #[cfg(target_os = "emscripten")]
+        self.handle_typed(screen::take_typed());
+        // Screens may drop the connection while handling a message,
+        // so the lock must not be held while they do.
+        let messages = {
+            let mut messages = Vec::new();
+            if let Some(ref receiver) = *RECEIVER.lock().unwrap() {
+                while let Some(message) = receiver.try_recv() {
+                    messages.push(message);
                 }
             }
+            messages
+        };
+        for message in messages {
+            if let ServerMessage::System { text } = message {
+                self.notice.show(text);
+                continue;
+            }
+            if let Some(screen) = self.screen.handle(screen::Event::Message(message)) {
+                self.screen = screen;
+            }
+            if RECEIVER.lock().unwrap().is_none() {
+                break;
+            }
         }
     }
     fn draw(&mut self, framebuffer: &mut ugli::Framebuffer) {
-        let event = screen::Event::Draw(framebuffer);
-        if let Some(screen) = self.screen.handle(event) {
-            self.screen = screen;
+        match connection_state() {
+            None | Some(connection::ConnectionState::Connected) => {}
+            Some(state) => {
+                self.status.draw(framebuffer, state);
+                return;
+            }
+        }
+        {
+            let event = screen::Event::Draw(framebuffer);
+            if let Some(screen) = self.screen.handle(event) {
+                self.screen = screen;
+            }
         }
+        self.notice.draw(framebuffer);
     }
     fn handle_event(&mut self, event: codevisual::Event) {
+        // Text typed before a key press is handled before it, so Enter sends all of it.
+        #[cfg(target_os = "emscripten")]
+        self.handle_typed(screen::take_typed());
+        #[cfg(not(target_os = "emscripten"))]
+        let typed: Vec<char> = match event {
+            codevisual::Event::KeyDown { key } => key_char(key).into_iter().collect(),
+            _ => Vec::new(),
+        };
+        match connection_state() {
+            None | Some(connection::ConnectionState::Connected) => {}
+            Some(state) => {
+                match self.status.handle(event) {
+                    Some(screen::StatusAction::Retry) => {
+                        if state == connection::ConnectionState::Failed {
+                            if let Some(ref receiver) = *RECEIVER.lock().unwrap() {
+                                *CREDENTIALS.lock().unwrap() = receiver.credentials();
+                            }
+                            self.screen = connect(&self.app);
+                        } else if let Some(ref receiver) = *RECEIVER.lock().unwrap() {
+                            receiver.retry();
+                        }
+                    }
+                    Some(screen::StatusAction::ChangeServer) => {
+                        if let Some(receiver) = RECEIVER.lock().unwrap().take() {
+                            receiver.close();
+                        }
+                        self.screen = Box::new(NicknameScreen::new(&self.app));
+                    }
+                    None => {}
+                }
+                return;
+            }
+        }
         if let Some(screen) = self.screen.handle(screen::Event::Event(event)) {
             self.screen = screen;
         }
+        #[cfg(not(target_os = "emscripten"))]
+        self.handle_typed(typed);
     }
     fn new(app: &Rc<codevisual::App>, mut resources: Resources) -> Self {
         resources.ground_texture.set_wrap_mode(ugli::WrapMode::Repeat);
         resources.grass_texture.set_wrap_mode(ugli::WrapMode::Repeat);
         unsafe { RESOURCES = Box::into_raw(Box::new(resources)); }
+        #[cfg(not(target_os = "emscripten"))]
+        {
+            if let Some(replay) = REPLAY.lock().unwrap().take() {
+                return Self {
+                    app: app.clone(),
+                    screen: Box::new(ReplayScreen::new(app, replay)),
+                    status: screen::ConnectionStatus::new(app),
+                    notice: screen::Notice::new(app),
+                };
+            }
+        }
         Self {
+            app: app.clone(),
             screen: Box::new(NicknameScreen::new(app)),
+            status: screen::ConnectionStatus::new(app),
+            notice: screen::Notice::new(app),
         }
     }
 }
 
 fn connect(app: &Rc<codevisual::App>) -> Box<Screen> {
-    let (sender, receiver) = connection::connect(&NICK.lock().unwrap(), &HOST.lock().unwrap(), *PORT.lock().unwrap());
+    let credentials = CREDENTIALS.lock().unwrap().clone();
+    let (sender, receiver) = connection::connect(&NICK.lock().unwrap(), credentials, &HOST.lock().unwrap(), *PORT.lock().unwrap());
     *RECEIVER.lock().unwrap() = Some(receiver);
     Box::new(screen::Lobby::new(app, NICK.lock().unwrap().clone(), sender))
 }
@@ -103,6 +227,7 @@ const DEFAULT_PORT: u16 = 8008;
 #[cfg(target_os = "emscripten")]
 fn main() {
     webby::emscripten::run_script(include_str!("web.js"));
+    screen::listen_for_text();
     *PORT.lock().unwrap() = DEFAULT_PORT;
     *HOST.lock().unwrap() = String::from("play.kuviman.com");
     codevisual::run::<TrollInvasion>();
@@ -110,11 +235,26 @@ fn main() {
 
 #[cfg(not(target_os = "emscripten"))]
 fn main() {
+    let working_dir = std::env::current_dir().unwrap();
     std::env::set_current_dir("static").unwrap();
     let mut port: u16 = DEFAULT_PORT;
     let mut host = None;
     let mut start_server = false;
     let mut nickname = None;
+    let mut generate_players: Option<usize> = None;
+    let mut seed: Option<u64> = None;
+    let mut map_size: usize = 4;
+    let mut density: f64 = 0.25;
+    let mut replay_path: Option<String> = None;
+    let mut grace: u64 = 60;
+    let mut rate_limits: Vec<String> = Vec::new();
+    let mut no_guests = false;
+    let mut status_port = None;
+    let mut turn_time: u64 = 60;
+    let mut password = None;
+    let mut animation_speed: f64 = 1.0;
+    let mut accounts_file = None;
+    let mut replay_dir = None;
 
     {
         let mut ap = argparse::ArgumentParser::new();
@@ -122,15 +262,78 @@ fn main() {
         ap.refer(&mut port).add_option(&["-p", "--port"], argparse::Store, "Specify port");
         ap.refer(&mut host).add_option(&["-c", "--connect"], argparse::StoreOption, "Start client, connect to specified host");
         ap.refer(&mut nickname).add_option(&["--nick"], argparse::StoreOption, "Nickname");
+        ap.refer(&mut password).add_option(&["--password"], argparse::StoreOption, "Password of a registered nickname");
         ap.refer(&mut start_server).add_option(&["-s", "--server"], argparse::StoreTrue, "Start server");
+        ap.refer(&mut grace).add_option(&["--grace"], argparse::Store, "Seconds to hold the seat of a disconnected player");
+        ap.refer(&mut no_guests).add_option(&["--no-guests"], argparse::StoreTrue, "Only allow registered nicknames to play");
+        ap.refer(&mut turn_time).add_option(&["--turn-time"], argparse::Store, "Default seconds per turn, 0 for no limit");
+        ap.refer(&mut accounts_file).add_option(&["--accounts"], argparse::StoreOption, "File to keep registered nicknames in, accounts.txt by default");
+        ap.refer(&mut replay_dir).add_option(&["--replays"], argparse::StoreOption, "Directory to record games to, replays by default");
+        ap.refer(&mut status_port).add_option(&["--status-port"], argparse::StoreOption, "Also serve the JSON status on this port");
+        ap.refer(&mut rate_limits).add_option(&["--rate-limit"], argparse::Collect, "Per-connection limit as class=rate/burst (session, lobby, game, hover, chat or flood)");
+        ap.refer(&mut generate_players).add_option(&["--generate-map"], argparse::StoreOption, "Print a generated map for given number of players and exit");
+        ap.refer(&mut seed).add_option(&["--seed"], argparse::StoreOption, "Map generator seed");
+        ap.refer(&mut map_size).add_option(&["--map-size"], argparse::Store, "Generated map radius");
+        ap.refer(&mut density).add_option(&["--density"], argparse::Store, "Generated map tree density");
+        ap.refer(&mut animation_speed).add_option(&["--anim-speed"], argparse::Store, "Speed of troll animations, 0 to turn them off");
+        ap.refer(&mut replay_path).add_option(&["--replay"], argparse::StoreOption, "Watch a recorded game");
         ap.parse_args_or_exit();
     }
 
+    if let Some(players) = generate_players {
+        if players < 2 || players > 6 {
+            eprintln!("Can only generate maps for 2 to 6 players");
+            std::process::exit(1);
+        }
+        let seed = seed.unwrap_or_else(|| {
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+        });
+        print!("{}", mapgen::generate_map(players, seed, map_size, density).to_text());
+        return;
+    }
+
+    if animation_speed < 0.0 {
+        eprintln!("Animation speed can not be negative");
+        std::process::exit(1);
+    }
+    *ANIMATION_SPEED.lock().unwrap() = animation_speed;
+
+    if let Some(path) = replay_path {
+        match replay::load(working_dir.join(&path)) {
+            Ok(messages) => *REPLAY.lock().unwrap() = Some(messages),
+            Err(e) => {
+                eprintln!("Failed to load replay {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        codevisual::run::<TrollInvasion>();
+        return;
+    }
+
     if start_server {
+        let mut limits = limits::RateLimits::default();
+        for spec in &rate_limits {
+            if let Err(e) = limits.set(spec) {
+                eprintln!("Bad rate limit: {}", e);
+                std::process::exit(1);
+            }
+        }
+        let options = server::Options {
+            port,
+            grace,
+            limits,
+            guests: !no_guests,
+            status_port,
+            turn_time,
+            // Relative to where the server was started, not the static directory
+            // that is served to the web client.
+            accounts_file: working_dir.join(accounts_file.unwrap_or_else(|| String::from(accounts::ACCOUNTS_FILE))),
+            replay_dir: working_dir.join(replay_dir.unwrap_or_else(|| String::from(replay::REPLAY_DIR))),
+        };
         if host.is_some() {
-            std::thread::spawn(move || { server::run(port) });
+            std::thread::spawn(move || { server::run(options) });
         } else {
-            server::run(port);
+            server::run(options);
         }
     } else if host.is_none() {
         host = Some(String::from("play.kuviman.com"));
@@ -148,6 +351,9 @@ fn main() {
             }
             *nick = nick.trim().to_owned();
         }
+        if let Some(password) = password {
+            *CREDENTIALS.lock().unwrap() = connection::Credentials::Password(password);
+        }
         codevisual::run::<TrollInvasion>();
     }
 }
@@ -0,0 +1,232 @@
+use ::*;
+
+pub const DEFAULT_MAP: &str = "Ring";
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            MapError::Io(ref e) => write!(f, "{}", e),
+            MapError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for MapError {
+    fn from(e: std::io::Error) -> Self {
+        MapError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapInfo {
+    pub name: String,
+    pub author: String,
+    pub players: usize,
+}
+
+// A map file has "name", "author" and "players" header lines followed by
+// rows in the mapLine cell encoding. Owners are player slots 'A', 'B', ...
+// Lines starting with a single '#' are comments, and every line that is
+// not a comment or a header is a row. Cells are in doubled coordinates,
+// so only every other column of a row, alternating between rows, is land.
+#[derive(Debug, Clone)]
+pub struct GameMap {
+    pub info: MapInfo,
+    pub cells: Vec<Vec<Option<GameCell>>>,
+}
+
+impl GameMap {
+    pub fn parse(text: &str) -> Result<Self, MapError> {
+        let mut name = None;
+        let mut author = String::new();
+        let mut players = None;
+        let mut cells = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| MapError::Parse { line: index + 1, message };
+            let line = line.trim();
+            if line.is_empty() || (line.starts_with('#') && !line.starts_with("##")) {
+                continue;
+            }
+            match line.split_whitespace().next() {
+                Some("name") | Some("author") | Some("players") => {}
+                _ => {
+                    cells.push(parse_map_line(line).map_err(|reason| error(format!("{:?}", reason)))?);
+                    continue;
+                }
+            }
+            let args = split_args(line).map_err(|reason| error(format!("{:?}", reason)))?;
+            match (args.get(0).map(String::as_str), args.get(1)) {
+                (Some("name"), Some(value)) => name = Some(value.clone()),
+                (Some("author"), Some(value)) => author = value.clone(),
+                (Some("players"), Some(value)) => {
+                    players = Some(value.parse().map_err(|_| error(format!("invalid player count {:?}", value)))?);
+                }
+                _ => return Err(error(format!("unexpected line {:?}", line))),
+            }
+        }
+        let error = |message: &str| MapError::Parse { line: 0, message: message.to_owned() };
+        let name = name.ok_or_else(|| error("missing name"))?;
+        let players: usize = players.ok_or_else(|| error("missing player count"))?;
+        if players < 2 || players > 6 {
+            return Err(error("player count must be from 2 to 6"));
+        }
+        if cells.is_empty() {
+            return Err(error("no map rows"));
+        }
+        for (i, line) in cells.iter().enumerate() {
+            for (j, cell) in line.iter().enumerate() {
+                if cell.is_some() && (i + j) % 2 != 0 {
+                    return Err(error(&format!("cell in row {} column {} is off the hex grid", i + 1, j + 1)));
+                }
+            }
+        }
+        for cell in cells.iter().flat_map(|line| line.iter()) {
+            if let Some(GameCell::Populated { owner, count }) = *cell {
+                if count < 1 || count > MAX_TROLLS {
+                    return Err(error(&format!("troll count {} is not from 1 to {}", count, MAX_TROLLS)));
+                }
+                if owner < 'A' || owner >= (b'A' + players as u8) as char {
+                    return Err(error(&format!("unknown player slot {:?}", owner)));
+                }
+            }
+        }
+        for slot in (0..players).map(|i| (b'A' + i as u8) as char) {
+            let has_cells = cells.iter().flat_map(|line| line.iter()).any(|cell| match *cell {
+                Some(GameCell::Populated { owner, .. }) => owner == slot,
+                _ => false,
+            });
+            if !has_cells {
+                return Err(error(&format!("player slot {:?} has no cells", slot)));
+            }
+        }
+        Ok(Self {
+            info: MapInfo { name, author, players },
+            cells,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut result = format!("name {}\nauthor {}\nplayers {}\n\n",
+                                 quote(&self.info.name), quote(&self.info.author), self.info.players);
+        for line in &self.cells {
+            result += &format_map_line(line);
+            result.push('\n');
+        }
+        result
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self, MapError> {
+        use std::io::Read;
+        let mut text = String::new();
+        std::fs::File::open(path)?.read_to_string(&mut text)?;
+        Self::parse(&text)
+    }
+
+    pub fn builtin() -> Self {
+        Self::parse(include_str!("../static/maps/ring.map")).expect("Invalid built-in map")
+    }
+
+    // Loads every *.map file in the directory, skipping broken ones.
+    pub fn load_all<P: AsRef<std::path::Path>>(dir: P) -> BTreeMap<String, Self> {
+        let mut maps = BTreeMap::new();
+        let builtin = Self::builtin();
+        maps.insert(builtin.info.name.clone(), builtin);
+        let entries = match std::fs::read_dir(dir.as_ref()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to read maps from {:?}: {}", dir.as_ref(), e);
+                return maps;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            if path.extension().map_or(true, |extension| extension != "map") {
+                continue;
+            }
+            match Self::load(&path) {
+                Ok(map) => {
+                    eprintln!("Loaded map {:?} from {:?}", map.info.name, path);
+                    maps.insert(map.info.name.clone(), map);
+                }
+                Err(e) => eprintln!("Failed to load map {:?}: {}", path, e),
+            }
+        }
+        maps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_starting_with_land_are_not_comments() {
+        let map = GameMap::parse(include_str!("../static/maps/duel.map")).unwrap();
+        assert_eq!(map.cells.len(), 7);
+        assert_eq!(map.cells[0][0], Some(GameCell::Empty));
+        assert_eq!(map.cells[3][1], Some(GameCell::Populated { count: 3, owner: 'A' }));
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let map = GameMap::parse("# comment\nname Test\nplayers 2\n#another\n1A|__|1B\n").unwrap();
+        assert_eq!(map.info.name, "Test");
+        assert_eq!(map.cells.len(), 1);
+    }
+
+    #[test]
+    fn troll_counts_are_checked() {
+        assert!(GameMap::parse("name Test\nplayers 2\n9A|__|1B\n").is_err());
+        assert!(GameMap::parse("name Test\nplayers 2\n0A|__|1B\n").is_err());
+        assert!(GameMap::parse("name Test\nplayers 2\n8A|__|1B\n").is_ok());
+    }
+
+    #[test]
+    fn one_cell_rows() {
+        let map = GameMap::parse("name Test\nplayers 2\n1A|__|1B\n__|##\n##\n").unwrap();
+        assert_eq!(map.cells.len(), 3);
+        assert_eq!(map.cells[2], vec![Some(GameCell::Empty)]);
+    }
+
+    #[test]
+    fn headers_may_contain_bars() {
+        let map = GameMap::parse("name \"a|b\"\nauthor \"c|d\"\nplayers 2\n1A|__|1B\n").unwrap();
+        assert_eq!(map.info.name, "a|b");
+        assert_eq!(map.info.author, "c|d");
+        assert_eq!(map.cells.len(), 1);
+    }
+
+    #[test]
+    fn cells_must_be_on_the_hex_grid() {
+        assert!(GameMap::parse("name Test\nplayers 2\n1A|##|1B\n").is_err());
+        assert!(GameMap::parse("name Test\nplayers 2\n1A|__|1B\n##\n").is_err());
+        assert!(GameMap::parse("name Test\nplayers 2\n1A|__|1B\n__|##\n").is_ok());
+    }
+
+    #[test]
+    fn every_slot_needs_cells() {
+        assert!(GameMap::parse("name Test\nplayers 3\n1A|__|1B\n").is_err());
+        assert!(GameMap::parse("name Test\nplayers 3\n1A|__|1B|__|1C\n").is_ok());
+    }
+
+    #[test]
+    fn text_round_trip() {
+        for map in GameMap::load_all(concat!(env!("CARGO_MANIFEST_DIR"), "/static/maps")).values() {
+            let parsed = GameMap::parse(&map.to_text()).unwrap();
+            assert_eq!(parsed.info, map.info);
+            assert_eq!(parsed.cells, map.cells);
+        }
+    }
+}
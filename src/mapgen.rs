@@ -0,0 +1,181 @@
+use ::*;
+
+use map::{GameMap, MapInfo};
+
+pub const MIN_SIZE: usize = 2;
+pub const MAX_SIZE: usize = 10;
+pub const MAX_DENSITY: f64 = 0.6;
+
+const START_TROLLS: usize = 3;
+const ATTEMPTS: u64 = 16;
+
+// Corners of the hexagon, taken for each player count so that starts
+// are spread as evenly around the board as possible.
+const CORNERS: [&[usize]; 5] = [
+    &[0, 3],
+    &[0, 2, 4],
+    &[0, 1, 3, 4],
+    &[0, 1, 2, 3, 4],
+    &[0, 1, 2, 3, 4, 5],
+];
+
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+    // splitmix64
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Cells are addressed with axial hex coordinates (q, r) inside a hexagon
+// of the given radius. Rotating by 60 degrees maps (q, r) to (-r, q + r).
+fn rotate((q, r): (isize, isize)) -> (isize, isize) {
+    (-r, q + r)
+}
+
+fn inside((q, r): (isize, isize), radius: isize) -> bool {
+    q.abs() <= radius && r.abs() <= radius && (q + r).abs() <= radius
+}
+
+fn axial_neighbours((q, r): (isize, isize)) -> [(isize, isize); 6] {
+    [(q + 1, r), (q - 1, r), (q, r + 1), (q, r - 1), (q + 1, r - 1), (q - 1, r + 1)]
+}
+
+// Cells with the same representative are rotations of each other.
+fn orbit_representative(mut pos: (isize, isize)) -> (isize, isize) {
+    let mut result = pos;
+    for _ in 0..5 {
+        pos = rotate(pos);
+        result = std::cmp::min(result, pos);
+    }
+    result
+}
+
+fn to_doubled((q, r): (isize, isize), radius: isize) -> Vec2<usize> {
+    vec2((r + radius) as usize, (2 * q + r + 2 * radius + radius % 2) as usize)
+}
+
+fn try_generate(players: usize, radius: isize, density: f64, random: &mut Random) -> Option<Vec<Vec<Option<GameCell>>>> {
+    let mut corners = vec![(radius, 0)];
+    for i in 0..5 {
+        let next = rotate(corners[i]);
+        corners.push(next);
+    }
+    let mut cells = Vec::new();
+    for r in -radius..radius + 1 {
+        for q in -radius..radius + 1 {
+            if inside((q, r), radius) {
+                cells.push((q, r));
+            }
+        }
+    }
+
+    // Trees are decided once per rotation orbit, keeping the board symmetric.
+    let mut orbit_trees = BTreeMap::new();
+    let mut trees = std::collections::BTreeSet::new();
+    for &pos in &cells {
+        let protected = pos == (0, 0) || corners.iter().any(|&corner| {
+            corner == pos || axial_neighbours(corner).contains(&pos)
+        });
+        let representative = orbit_representative(pos);
+        let tree = *orbit_trees.entry(representative).or_insert_with(|| random.next_f64() < density);
+        if tree && !protected {
+            trees.insert(pos);
+        }
+    }
+
+    // Land not reachable from the center becomes forest, so the board is connected.
+    let mut reachable = std::collections::BTreeSet::new();
+    let mut queue = std::collections::VecDeque::new();
+    reachable.insert((0, 0));
+    queue.push_back((0, 0));
+    while let Some(pos) = queue.pop_front() {
+        for &next in axial_neighbours(pos).iter() {
+            if inside(next, radius) && !trees.contains(&next) && reachable.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+    if !corners.iter().all(|corner| reachable.contains(corner)) {
+        return None;
+    }
+
+    let (rows, cols) = (2 * radius + 1, 4 * radius + 1 + radius % 2);
+    let mut map = vec![vec![None; cols as usize]; rows as usize];
+    for &pos in &reachable {
+        let cell = to_doubled(pos, radius);
+        map[cell.x][cell.y] = Some(GameCell::Empty);
+    }
+    for (slot, &corner) in CORNERS[players - 2].iter().enumerate() {
+        let cell = to_doubled(corners[corner], radius);
+        map[cell.x][cell.y] = Some(GameCell::Populated {
+            count: START_TROLLS,
+            owner: (b'A' + slot as u8) as char,
+        });
+    }
+    Some(map)
+}
+
+// Generates a hexagonal board that looks the same after every 60 degree
+// rotation, with players starting in its corners. Any two corners are
+// therefore equivalent, whatever the number of players.
+pub fn generate(players: usize, seed: u64, size: usize, density: f64) -> Vec<Vec<Option<GameCell>>> {
+    assert!(players >= 2 && players <= 6, "Can only generate maps for 2 to 6 players");
+    let radius = clamp(size, MIN_SIZE, MAX_SIZE) as isize;
+    let density = clamp(density, 0.0, MAX_DENSITY);
+    let mut random = Random::new(seed);
+    for _ in 0..ATTEMPTS {
+        if let Some(map) = try_generate(players, radius, density, &mut random) {
+            return map;
+        }
+    }
+    try_generate(players, radius, 0.0, &mut random).unwrap()
+}
+
+pub fn generate_map(players: usize, seed: u64, size: usize, density: f64) -> GameMap {
+    GameMap {
+        info: MapInfo {
+            name: format!("Random {}", seed),
+            author: String::from("generator"),
+            players,
+        },
+        cells: generate(players, seed, size, density),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_maps_load_back() {
+        for players in 2..7 {
+            for size in MIN_SIZE..MAX_SIZE + 1 {
+                for seed in 0..4 {
+                    let map = generate_map(players, seed, size, 0.3);
+                    let parsed = GameMap::parse(&map.to_text()).unwrap();
+                    assert_eq!(parsed.info, map.info);
+                    assert_eq!(parsed.cells, map.cells, "{} players, size {}, seed {}", players, size, seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        assert_eq!(generate(4, 42, 5, 0.25), generate(4, 42, 5, 0.25));
+    }
+}
@@ -1,11 +1,132 @@
 use ::*;
 
-#[derive(Copy, Clone)]
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const MAX_TROLLS: usize = 8;
+pub const CAPABILITIES: &[&str] = &["spectate", "hover", "resume", "accounts", "chat", "undo"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    ClientTooOld,
+    NickTaken,
+    NickEmpty,
+    NickTooLong,
+    NickInvalid,
+    SessionExpired,
+    Kicked,
+    Flooding,
+    NickRegistered,
+    WrongPassword,
+    PasswordEmpty,
+    PasswordTooLong,
+    GuestsDisabled,
+}
+
+impl RejectReason {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            RejectReason::ClientTooOld => "client too old, please update",
+            RejectReason::NickTaken => "nickname is already taken",
+            RejectReason::NickEmpty => "nickname can not be empty",
+            RejectReason::NickTooLong => "nickname is too long",
+            RejectReason::NickInvalid => "nickname contains illegal characters",
+            RejectReason::SessionExpired => "session expired, please log in again",
+            RejectReason::Kicked => "you were kicked from the server",
+            RejectReason::Flooding => "disconnected for sending too many messages",
+            RejectReason::NickRegistered => "nickname is registered, enter its password",
+            RejectReason::WrongPassword => "wrong nickname or password",
+            RejectReason::PasswordEmpty => "password can not be empty",
+            RejectReason::PasswordTooLong => "password is too long",
+            RejectReason::GuestsDisabled => "guests are not allowed, log in or register",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            RejectReason::ClientTooOld => write!(f, "clientTooOld"),
+            RejectReason::NickTaken => write!(f, "nickTaken"),
+            RejectReason::NickEmpty => write!(f, "nickEmpty"),
+            RejectReason::NickTooLong => write!(f, "nickTooLong"),
+            RejectReason::NickInvalid => write!(f, "nickInvalid"),
+            RejectReason::SessionExpired => write!(f, "sessionExpired"),
+            RejectReason::Kicked => write!(f, "kicked"),
+            RejectReason::Flooding => write!(f, "flooding"),
+            RejectReason::NickRegistered => write!(f, "nickRegistered"),
+            RejectReason::WrongPassword => write!(f, "wrongPassword"),
+            RejectReason::PasswordEmpty => write!(f, "passwordEmpty"),
+            RejectReason::PasswordTooLong => write!(f, "passwordTooLong"),
+            RejectReason::GuestsDisabled => write!(f, "guestsDisabled"),
+        }
+    }
+}
+
+impl std::str::FromStr for RejectReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clientTooOld" => Ok(RejectReason::ClientTooOld),
+            "nickTaken" => Ok(RejectReason::NickTaken),
+            "nickEmpty" => Ok(RejectReason::NickEmpty),
+            "nickTooLong" => Ok(RejectReason::NickTooLong),
+            "nickInvalid" => Ok(RejectReason::NickInvalid),
+            "sessionExpired" => Ok(RejectReason::SessionExpired),
+            "kicked" => Ok(RejectReason::Kicked),
+            "flooding" => Ok(RejectReason::Flooding),
+            "nickRegistered" => Ok(RejectReason::NickRegistered),
+            "wrongPassword" => Ok(RejectReason::WrongPassword),
+            "passwordEmpty" => Ok(RejectReason::PasswordEmpty),
+            "passwordTooLong" => Ok(RejectReason::PasswordTooLong),
+            "guestsDisabled" => Ok(RejectReason::GuestsDisabled),
+            _ => Err(()),
+        }
+    }
+}
+
+pub const MAX_NICK_LENGTH: usize = 15;
+
+// Checks everything about a nickname except whether it is taken.
+pub fn validate_nick(nick: &str) -> Result<(), RejectReason> {
+    if nick.trim().is_empty() {
+        Err(RejectReason::NickEmpty)
+    } else if nick.chars().count() > MAX_NICK_LENGTH {
+        Err(RejectReason::NickTooLong)
+    } else if nick.trim() != nick || nick.chars().any(|c| c.is_control()) {
+        Err(RejectReason::NickInvalid)
+    } else {
+        Ok(())
+    }
+}
+
+pub const MAX_PASSWORD_LENGTH: usize = 64;
+
+pub fn validate_password(password: &str) -> Result<(), RejectReason> {
+    if password.is_empty() {
+        Err(RejectReason::PasswordEmpty)
+    } else if password.chars().count() > MAX_PASSWORD_LENGTH {
+        Err(RejectReason::PasswordTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PlayType {
     Player,
     Spectator,
 }
 
+impl std::fmt::Display for PlayType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PlayType::Player => write!(f, "player"),
+            PlayType::Spectator => write!(f, "spectator"),
+        }
+    }
+}
+
 impl std::str::FromStr for PlayType {
     type Err = ();
 
@@ -18,6 +139,41 @@ impl std::str::FromStr for PlayType {
     }
 }
 
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+// Who gets to read a chat message: everyone in the main lobby,
+// everyone in the sender's game, or only the spectators of it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChatScope {
+    Lobby,
+    Game,
+    Spectators,
+}
+
+impl std::fmt::Display for ChatScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ChatScope::Lobby => write!(f, "lobby"),
+            ChatScope::Game => write!(f, "game"),
+            ChatScope::Spectators => write!(f, "spectators"),
+        }
+    }
+}
+
+impl std::str::FromStr for ChatScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lobby" => Ok(ChatScope::Lobby),
+            "game" => Ok(ChatScope::Game),
+            "spectators" => Ok(ChatScope::Spectators),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServerMessage {
     ReadyStatus {
         nick: String,
@@ -29,8 +185,10 @@ pub enum ServerMessage {
         nick: String,
         color: char,
     },
+    // Seconds left until the turn ends, if the game has a turn clock.
     Turn {
         nick: String,
+        time_left: Option<f64>,
     },
     SelectCell {
         row: usize,
@@ -42,6 +200,8 @@ pub enum ServerMessage {
     },
     UpgradePhase,
     EnergyLeft(usize),
+    // Upgrades made this phase that can still be undone.
+    PendingUpgrades(usize),
     GameList {
         name: String,
         player_count: usize,
@@ -68,102 +228,909 @@ pub enum ServerMessage {
         cells: Vec<Vec2<usize>>,
     },
     EndMap,
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    Rejected {
+        reason: RejectReason,
+    },
+    MapInfo {
+        name: String,
+        players: usize,
+        author: String,
+    },
+    // Token to resume the session with, and for how many seconds
+    // the seat is held after the connection drops.
+    Session {
+        token: String,
+        grace: u64,
+    },
+    ServerError {
+        message: String,
+    },
+    // Announcement from the server admin.
+    System {
+        text: String,
+    },
+    Chat {
+        scope: ChatScope,
+        nick: String,
+        text: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum ParseErrorReason {
+    EmptyMessage,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument(String),
+    InvalidCell(String),
+    UnterminatedQuote,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub command: String,
+    pub position: usize,
+    pub reason: ParseErrorReason,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use ParseErrorReason::*;
+        match self.reason {
+            EmptyMessage => write!(f, "empty message"),
+            UnknownCommand => write!(f, "unknown command {:?}", self.command),
+            MissingArgument => write!(f, "{}: missing argument #{}", self.command, self.position),
+            InvalidArgument(ref arg) => write!(f, "{}: invalid argument #{}: {:?}", self.command, self.position, arg),
+            InvalidCell(ref cell) => write!(f, "{}: invalid cell in argument #{}: {:?}", self.command, self.position, cell),
+            UnterminatedQuote => write!(f, "{}: unterminated quote", self.command),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn description(&self) -> &str {
+        "failed to parse message"
+    }
+}
+
+// Arguments containing whitespace, quotes, backslashes, ':' or ',' are
+// wrapped in double quotes, with '"' and '\' escaped by a backslash.
+pub fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.chars().any(|c| c.is_whitespace() || "\"\\:,".contains(c)) {
+        return arg.to_owned();
+    }
+    let mut result = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            _ => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+pub fn split_args(line: &str) -> Result<Vec<String>, ParseErrorReason> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut arg = String::new();
+        match chars.next() {
+            None => return Ok(args),
+            Some('"') => {
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push('\n'),
+                            Some('r') => arg.push('\r'),
+                            Some(c) => arg.push(c),
+                            None => return Err(ParseErrorReason::UnterminatedQuote),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseErrorReason::UnterminatedQuote),
+                    }
+                }
+            }
+            Some(c) => {
+                arg.push(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(c);
+                    chars.next();
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+pub fn unquote(arg: &str) -> String {
+    split_args(arg).ok().and_then(|args| args.into_iter().next()).unwrap_or_default()
+}
+
+// Returns the byte index of the first separator that is not inside quotes.
+pub fn find_unquoted(s: &str, separator: char) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted {
+            match c {
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if c == separator {
+            return Some(index);
+        }
+    }
+    None
+}
+
+pub fn split_unquoted(mut s: &str, separator: char) -> Vec<&str> {
+    let mut result = Vec::new();
+    while let Some(index) = find_unquoted(s, separator) {
+        result.push(&s[..index]);
+        s = &s[index + separator.len_utf8()..];
+    }
+    result.push(s);
+    result
+}
+
+struct Args {
+    command: String,
+    position: usize,
+    args: std::vec::IntoIter<String>,
+}
+
+impl Args {
+    fn new(message: &str) -> Result<Self, ParseError> {
+        let mut args = split_args(message).map_err(|reason| ParseError {
+            command: message.split_whitespace().next().unwrap_or("").to_owned(),
+            position: 0,
+            reason,
+        })?.into_iter();
+        Ok(Args {
+            command: args.next().unwrap_or_default(),
+            position: 0,
+            args,
+        })
+    }
+    fn error(&self, reason: ParseErrorReason) -> ParseError {
+        ParseError {
+            command: self.command.clone(),
+            position: self.position,
+            reason,
+        }
+    }
+    fn peek(&self) -> Option<&str> {
+        self.args.as_slice().first().map(String::as_str)
+    }
+    fn try_next(&mut self) -> Option<String> {
+        let arg = self.args.next();
+        if arg.is_some() {
+            self.position += 1;
+        }
+        arg
+    }
+    fn next(&mut self) -> Result<String, ParseError> {
+        match self.try_next() {
+            Some(arg) => Ok(arg),
+            None => {
+                self.position += 1;
+                Err(self.error(ParseErrorReason::MissingArgument))
+            }
+        }
+    }
+    fn parse<T: std::str::FromStr>(&mut self) -> Result<T, ParseError> {
+        let arg = self.next()?;
+        arg.parse().map_err(|_| self.error(ParseErrorReason::InvalidArgument(arg)))
+    }
+    fn try_parse<T: std::str::FromStr>(&mut self) -> Result<Option<T>, ParseError> {
+        match self.peek() {
+            Some(_) => self.parse().map(Some),
+            None => Ok(None),
+        }
+    }
 }
 
 impl ServerMessage {
-    pub fn parse(message: &str) -> Option<Self> {
+    pub fn to_wire(&self) -> String {
+        use ServerMessage::*;
+        match *self {
+            ReadyStatus { ref nick, ready } => format!("readyStatus {} {}", quote(nick), ready),
+            MapLine(index, ref cells) => format!("mapLine {} {}", index, format_map_line(cells)),
+            GameStart => String::from("gameStart"),
+            PlayerColor { ref nick, color } => format!("playerColor {} {}", quote(nick), color),
+            Turn { ref nick, time_left } => match time_left {
+                Some(time_left) => format!("turn {} {}", quote(nick), time_left),
+                None => format!("turn {}", quote(nick)),
+            },
+            SelectCell { row, col } => format!("selectCell {} {}", row, col),
+            DeselectCell => String::from("deselectCell"),
+            GameFinish { ref winner } => format!("gameFinish {}", quote(winner)),
+            UpgradePhase => String::from("upgradePhase"),
+            EnergyLeft(energy) => format!("energyLeft {}", energy),
+            PendingUpgrades(count) => format!("pendingUpgrades {}", count),
+            GameList { ref name, player_count } => format!("gameList {} {}", quote(name), player_count),
+            GameLeft { ref nick } => format!("gameLeft {}", quote(nick)),
+            GameEntered { ref name, typ } => format!("gameEntered {} {}", quote(name), typ),
+            HoverCell { ref nick, row, col } => format!("hover {} {} {}", quote(nick), row, col),
+            HoverNone { ref nick } => format!("hover {} none", quote(nick)),
+            SpectatorJoin { ref nick } => format!("spectatorJoin {}", quote(nick)),
+            CanMove { ref cells } => {
+                let mut result = String::from("canMove");
+                for cell in cells {
+                    result += &format!(" {} {}", cell.x, cell.y);
+                }
+                result
+            }
+            EndMap => String::from("endMap"),
+            Welcome { version, ref capabilities } => {
+                let mut result = format!("welcome {}", version);
+                for capability in capabilities {
+                    result += &format!(" {}", quote(capability));
+                }
+                result
+            }
+            Rejected { reason } => format!("rejected {}", reason),
+            MapInfo { ref name, players, ref author } => format!("mapInfo {} {} {}", quote(name), players, quote(author)),
+            Session { ref token, grace } => format!("session {} {}", quote(token), grace),
+            ServerError { ref message } => format!("serverError {}", quote(message)),
+            System { ref text } => format!("system {}", quote(text)),
+            Chat { scope, ref nick, ref text } => format!("chat {} {} {}", scope, quote(nick), quote(text)),
+        }
+    }
+
+    pub fn parse(message: &str) -> Result<Self, ParseError> {
         use ServerMessage::*;
-        let mut args = message.split_whitespace();
-        let command = args.next().unwrap();
-        Some(match command {
+        let mut args = Args::new(message)?;
+        let command = args.command.clone();
+        Ok(match command.as_str() {
+            "" => return Err(args.error(ParseErrorReason::EmptyMessage)),
             "readyStatus" => ReadyStatus {
-                nick: args.next().unwrap().to_owned(),
-                ready: args.next().unwrap().parse().unwrap(),
+                nick: args.next()?,
+                ready: args.parse()?,
             },
             "gameStart" => GameStart,
             "playerColor" => PlayerColor {
-                nick: args.next().unwrap().to_owned(),
-                color: args.next().unwrap().parse().unwrap(),
+                nick: args.next()?,
+                color: args.parse()?,
             },
             "turn" => Turn {
-                nick: args.next().unwrap().to_owned(),
+                nick: args.next()?,
+                time_left: args.try_parse()?,
             },
             "selectCell" => SelectCell {
-                row: args.next().unwrap().parse().unwrap(),
-                col: args.next().unwrap().parse().unwrap(),
+                row: args.parse()?,
+                col: args.parse()?,
             },
             "deselectCell" => DeselectCell,
             "gameFinish" => GameFinish {
-                winner: args.next().unwrap().to_owned(),
+                winner: args.next()?,
             },
             "upgradePhase" => UpgradePhase,
-            "energyLeft" => EnergyLeft(args.next().unwrap().parse().unwrap()),
+            "energyLeft" => EnergyLeft(args.parse()?),
+            "pendingUpgrades" => PendingUpgrades(args.parse()?),
             "mapLine" => {
-                let index = args.next().unwrap().parse().unwrap();
-                let cells = args.next().unwrap().split('|').map(|cell| {
-                    match cell {
-                        "##" => Some(GameCell::Empty),
-                        "__" => None,
-                        _ => {
-                            let (count, owner) = cell.split_at(cell.len() - 1);
-                            let count = count.parse().unwrap();
-                            let owner = owner.parse().unwrap();
-                            Some(GameCell::Populated {
-                                count,
-                                owner,
-                            })
-                        }
-                    }
-                }).collect();
+                let index = args.parse()?;
+                let cells = parse_map_line(&args.next()?).map_err(|reason| args.error(reason))?;
                 MapLine(index, cells)
             }
             "gameList" => GameList {
-                name: args.next().unwrap().to_owned(),
-                player_count: args.next().unwrap().parse().unwrap(),
+                name: args.next()?,
+                player_count: args.parse()?,
             },
             "gameEntered" => GameEntered {
-                name: args.next().unwrap().to_owned(),
-                typ: args.next().unwrap().parse().unwrap(),
+                name: args.next()?,
+                typ: args.parse()?,
             },
             "gameLeft" => GameLeft {
-                nick: args.next().unwrap().to_owned(),
+                nick: args.next()?,
             },
             "hover" => {
-                let nick = args.next().unwrap().to_owned();
-                let next = args.next().unwrap();
-                if next == "none" {
+                let nick = args.next()?;
+                if args.peek() == Some("none") {
                     HoverNone { nick }
                 } else {
                     HoverCell {
                         nick,
-                        row: next.parse().unwrap(),
-                        col: args.next().unwrap().parse().unwrap(),
+                        row: args.parse()?,
+                        col: args.parse()?,
                     }
                 }
             }
-            "spectatorJoin" => SpectatorJoin { nick: args.next().unwrap().parse().unwrap() },
+            "spectatorJoin" => SpectatorJoin { nick: args.next()? },
             "canMove" => CanMove {
                 cells: {
                     let mut cells = Vec::new();
-                    while let Some(arg) = args.next() {
-                        let row: usize = arg.parse().unwrap();
-                        let col: usize = args.next().unwrap().parse().unwrap();
+                    while let Some(arg) = args.try_next() {
+                        let row: usize = arg.parse().map_err(|_| args.error(ParseErrorReason::InvalidArgument(arg.clone())))?;
+                        let col: usize = args.parse()?;
                         cells.push(vec2(row, col));
                     }
                     cells
                 }
             },
             "endMap" => EndMap,
-            _ => return None
+            "welcome" => Welcome {
+                version: args.parse()?,
+                capabilities: {
+                    let mut capabilities = Vec::new();
+                    while let Some(capability) = args.try_next() {
+                        capabilities.push(capability);
+                    }
+                    capabilities
+                },
+            },
+            "rejected" => Rejected {
+                reason: args.parse()?,
+            },
+            "mapInfo" => MapInfo {
+                name: args.next()?,
+                players: args.parse()?,
+                author: args.next()?,
+            },
+            "session" => Session {
+                token: args.next()?,
+                grace: args.parse()?,
+            },
+            "serverError" => ServerError {
+                message: args.next()?,
+            },
+            "system" => System {
+                text: args.next()?,
+            },
+            "chat" => Chat {
+                scope: args.parse()?,
+                nick: args.next()?,
+                text: args.next()?,
+            },
+            _ => return Err(args.error(ParseErrorReason::UnknownCommand)),
         })
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapChoice {
+    Default,
+    Named(String),
+    Generated {
+        seed: u64,
+        size: usize,
+        density: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    // Logs in as a guest.
+    Login {
+        nick: String,
+    },
+    // Logs in to a registered account.
+    Authenticate {
+        nick: String,
+        password: String,
+    },
+    // Registers an account and logs in to it.
+    Register {
+        nick: String,
+        password: String,
+    },
+    Resume {
+        nick: String,
+        token: String,
+    },
+    Logout,
+    ListGames,
+    ListMaps,
+    // Turn time in seconds, 0 for no limit and None for the server default.
+    CreateGame {
+        name: String,
+        map: MapChoice,
+        turn_time: Option<u64>,
+    },
+    JoinGame {
+        name: String,
+        typ: PlayType,
+    },
+    LeaveGame,
+    Ready,
+    Unready,
+    SelectColor {
+        color: char,
+    },
+    HoverCell {
+        row: usize,
+        col: usize,
+    },
+    HoverNone,
+    Chat {
+        scope: ChatScope,
+        text: String,
+    },
+    NextPhase,
+    FullUp {
+        row: usize,
+        col: usize,
+    },
+    // Take back the last upgrade, or all of them, made this upgrade phase.
+    Undo,
+    UndoAll,
+    Cell {
+        row: usize,
+        col: usize,
+    },
+}
+
+impl ClientMessage {
+    pub fn hello() -> Self {
+        ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|&capability| capability.to_owned()).collect(),
+        }
+    }
+
+    pub fn to_wire(&self) -> String {
+        use ClientMessage::*;
+        match *self {
+            Hello { version, ref capabilities } => {
+                let mut result = format!("hello {}", version);
+                for capability in capabilities {
+                    result += &format!(" {}", quote(capability));
+                }
+                result
+            }
+            Login { ref nick } => format!("+{}", quote(nick)),
+            Authenticate { ref nick, ref password } => format!("login {} {}", quote(nick), quote(password)),
+            Register { ref nick, ref password } => format!("register {} {}", quote(nick), quote(password)),
+            Resume { ref nick, ref token } => format!("resume {} {}", quote(nick), quote(token)),
+            Logout => String::from("-"),
+            ListGames => String::from("listGames"),
+            ListMaps => String::from("listMaps"),
+            CreateGame { ref name, ref map, turn_time } => {
+                let mut result = match *map {
+                    MapChoice::Default => format!("createGame {}", quote(name)),
+                    MapChoice::Named(ref map) => format!("createGame {} map {}", quote(name), quote(map)),
+                    MapChoice::Generated { seed, size, density } => {
+                        format!("createGame {} generate {} {} {}", quote(name), seed, size, density)
+                    }
+                };
+                if let Some(turn_time) = turn_time {
+                    result += &format!(" time {}", turn_time);
+                }
+                result
+            }
+            JoinGame { ref name, typ } => format!("joinGame {} {}", quote(name), typ),
+            LeaveGame => String::from("leaveGame"),
+            Ready => String::from("ready"),
+            Unready => String::from("unready"),
+            SelectColor { color } => format!("selectColor {}", color),
+            HoverCell { row, col } => format!("hover {} {}", row, col),
+            HoverNone => String::from("hover none"),
+            Chat { scope, ref text } => format!("chat {} {}", scope, quote(text)),
+            NextPhase => String::from("next phase"),
+            FullUp { row, col } => format!("fullUp {} {}", row, col),
+            Undo => String::from("undo"),
+            UndoAll => String::from("undoAll"),
+            Cell { row, col } => format!("{} {}", row, col),
+        }
+    }
+
+    // Like to_wire, but without passwords and session tokens, for the server log.
+    pub fn to_log(&self) -> String {
+        use ClientMessage::*;
+        match *self {
+            Authenticate { ref nick, .. } => format!("login {} ***", quote(nick)),
+            Register { ref nick, .. } => format!("register {} ***", quote(nick)),
+            Resume { ref nick, .. } => format!("resume {} ***", quote(nick)),
+            _ => self.to_wire(),
+        }
+    }
+
+    pub fn parse(message: &str) -> Result<Self, ParseError> {
+        use ClientMessage::*;
+        if message.starts_with('+') {
+            return Ok(Login { nick: unquote(&message[1..]) });
+        }
+        let mut args = Args::new(message)?;
+        let command = args.command.clone();
+        Ok(match command.as_str() {
+            "" => return Err(args.error(ParseErrorReason::EmptyMessage)),
+            "hello" => Hello {
+                version: args.parse()?,
+                capabilities: {
+                    let mut capabilities = Vec::new();
+                    while let Some(capability) = args.try_next() {
+                        capabilities.push(capability);
+                    }
+                    capabilities
+                },
+            },
+            "login" => Authenticate {
+                nick: args.next()?,
+                password: args.next()?,
+            },
+            "register" => Register {
+                nick: args.next()?,
+                password: args.next()?,
+            },
+            "resume" => Resume {
+                nick: args.next()?,
+                token: args.next()?,
+            },
+            "-" => Logout,
+            "listGames" => ListGames,
+            "listMaps" => ListMaps,
+            "createGame" => CreateGame {
+                name: args.next()?,
+                // The keywords always come before the map name, so a map can be called anything.
+                map: match args.peek() {
+                    Some("map") => {
+                        args.next()?;
+                        MapChoice::Named(args.next()?)
+                    }
+                    Some("generate") => {
+                        args.next()?;
+                        MapChoice::Generated {
+                            seed: args.parse()?,
+                            size: args.parse()?,
+                            density: args.parse()?,
+                        }
+                    }
+                    _ => MapChoice::Default,
+                },
+                turn_time: if args.peek() == Some("time") {
+                    args.next()?;
+                    Some(args.parse()?)
+                } else {
+                    None
+                },
+            },
+            "joinGame" => JoinGame {
+                name: args.next()?,
+                typ: args.parse()?,
+            },
+            "leaveGame" => LeaveGame,
+            "ready" => Ready,
+            "unready" => Unready,
+            "selectColor" => SelectColor {
+                color: args.parse()?,
+            },
+            "hover" => {
+                if args.peek() == Some("none") {
+                    HoverNone
+                } else {
+                    HoverCell {
+                        row: args.parse()?,
+                        col: args.parse()?,
+                    }
+                }
+            }
+            "chat" => Chat {
+                scope: args.parse()?,
+                text: args.next()?,
+            },
+            "next" => match args.next()?.as_str() {
+                "phase" => NextPhase,
+                arg => return Err(args.error(ParseErrorReason::InvalidArgument(arg.to_owned()))),
+            },
+            "fullUp" => FullUp {
+                row: args.parse()?,
+                col: args.parse()?,
+            },
+            "undo" => Undo,
+            "undoAll" => UndoAll,
+            command => match command.parse() {
+                Ok(row) => Cell {
+                    row,
+                    col: args.parse()?,
+                },
+                Err(_) => return Err(args.error(ParseErrorReason::UnknownCommand)),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum GameCell {
     Empty,
     Populated {
         count: usize,
         owner: char,
     }
-}
\ No newline at end of file
+}
+
+pub fn parse_map_line(line: &str) -> Result<Vec<Option<GameCell>>, ParseErrorReason> {
+    line.split('|').map(|cell| {
+        match cell {
+            "##" => Ok(Some(GameCell::Empty)),
+            "__" => Ok(None),
+            _ => {
+                let invalid = || ParseErrorReason::InvalidCell(cell.to_owned());
+                let owner = cell.chars().last().ok_or_else(&invalid)?;
+                let count = cell[..cell.len() - owner.len_utf8()].parse().map_err(|_| invalid())?;
+                Ok(Some(GameCell::Populated {
+                    count,
+                    owner,
+                }))
+            }
+        }
+    }).collect()
+}
+
+pub fn format_map_line(cells: &[Option<GameCell>]) -> String {
+    let cells: Vec<String> = cells.iter().map(|cell| {
+        match *cell {
+            Some(GameCell::Empty) => String::from("##"),
+            None => String::from("__"),
+            Some(GameCell::Populated { count, owner }) => format!("{}{}", count, owner),
+        }
+    }).collect();
+    cells.join("|")
+}
+
+// Cells are stored in doubled coordinates: (row, col) with neighbours
+// two columns apart in the same row and one column apart in adjacent rows.
+pub fn neighbours(pos: Vec2<usize>) -> Vec<Vec2<usize>> {
+    let (row, col) = (pos.x as isize, pos.y as isize);
+    [(0, -2), (0, 2), (-1, -1), (-1, 1), (1, -1), (1, 1)].iter()
+        .map(|&(dr, dc)| (row + dr, col + dc))
+        .filter(|&(row, col)| row >= 0 && col >= 0)
+        .map(|(row, col)| vec2(row as usize, col as usize))
+        .collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small xorshift generator, so the tests need no extra dependencies and are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+        fn bool(&mut self) -> bool {
+            self.below(2) == 0
+        }
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len())]
+        }
+        fn string(&mut self) -> String {
+            const CHARS: &[char] = &['a', 'Z', '0', '9', ' ', '"', '\'', '\\', ',', ':', '\n', '\r', '\t',
+                '-', '+', '#', '|', '_', 'é', 'ж', '漢', '🙂'];
+            let length = self.below(12);
+            (0..length).map(|_| self.pick(CHARS)).collect()
+        }
+        fn strings(&mut self) -> Vec<String> {
+            (0..self.below(4)).map(|_| self.string()).collect()
+        }
+        fn color(&mut self) -> char {
+            self.pick(&['A', 'B', 'C', 'D', 'E', 'F'])
+        }
+        fn cell(&mut self) -> Option<GameCell> {
+            match self.below(3) {
+                0 => None,
+                1 => Some(GameCell::Empty),
+                _ => Some(GameCell::Populated { count: 1 + self.below(MAX_TROLLS), owner: self.color() }),
+            }
+        }
+        fn float(&mut self) -> f64 {
+            self.next() as f64 / 1e15
+        }
+    }
+
+    const REASONS: &[RejectReason] = &[
+        RejectReason::ClientTooOld, RejectReason::NickTaken, RejectReason::NickEmpty, RejectReason::NickTooLong,
+        RejectReason::NickInvalid, RejectReason::SessionExpired, RejectReason::Kicked, RejectReason::Flooding,
+        RejectReason::NickRegistered, RejectReason::WrongPassword, RejectReason::PasswordEmpty,
+        RejectReason::PasswordTooLong, RejectReason::GuestsDisabled,
+    ];
+    const SCOPES: &[ChatScope] = &[ChatScope::Lobby, ChatScope::Game, ChatScope::Spectators];
+    const PLAY_TYPES: &[PlayType] = &[PlayType::Player, PlayType::Spectator];
+
+    // One message of every variant. The match makes adding a variant without a test a compile error.
+    fn server_messages(rng: &mut Rng) -> Vec<ServerMessage> {
+        use ServerMessage::*;
+        let messages = vec![
+            ReadyStatus { nick: rng.string(), ready: rng.bool() },
+            MapLine(rng.below(100), (0..1 + rng.below(10)).map(|_| rng.cell()).collect()),
+            GameStart,
+            PlayerColor { nick: rng.string(), color: rng.color() },
+            Turn { nick: rng.string(), time_left: if rng.bool() { Some(rng.float()) } else { None } },
+            SelectCell { row: rng.below(100), col: rng.below(100) },
+            DeselectCell,
+            GameFinish { winner: rng.string() },
+            UpgradePhase,
+            EnergyLeft(rng.below(100)),
+            PendingUpgrades(rng.below(100)),
+            GameList { name: rng.string(), player_count: rng.below(7) },
+            GameLeft { nick: rng.string() },
+            GameEntered { name: rng.string(), typ: rng.pick(PLAY_TYPES) },
+            HoverCell { nick: rng.string(), row: rng.below(100), col: rng.below(100) },
+            HoverNone { nick: rng.string() },
+            SpectatorJoin { nick: rng.string() },
+            CanMove { cells: (0..rng.below(7)).map(|_| vec2(rng.below(100), rng.below(100))).collect() },
+            EndMap,
+            Welcome { version: rng.below(10) as u32, capabilities: rng.strings() },
+            Rejected { reason: rng.pick(REASONS) },
+            MapInfo { name: rng.string(), players: rng.below(7), author: rng.string() },
+            Session { token: rng.string(), grace: rng.next() },
+            ServerError { message: rng.string() },
+            System { text: rng.string() },
+            Chat { scope: rng.pick(SCOPES), nick: rng.string(), text: rng.string() },
+        ];
+        for message in &messages {
+            match *message {
+                ReadyStatus { .. } | MapLine(..) | GameStart | PlayerColor { .. } | Turn { .. } |
+                SelectCell { .. } | DeselectCell | GameFinish { .. } | UpgradePhase | EnergyLeft(_) |
+                PendingUpgrades(_) | GameList { .. } | GameLeft { .. } | GameEntered { .. } |
+                HoverCell { .. } | HoverNone { .. } | SpectatorJoin { .. } | CanMove { .. } | EndMap |
+                Welcome { .. } | Rejected { .. } | MapInfo { .. } | Session { .. } | ServerError { .. } |
+                System { .. } | Chat { .. } => {}
+            }
+        }
+        messages
+    }
+
+    fn client_messages(rng: &mut Rng) -> Vec<ClientMessage> {
+        use ClientMessage::*;
+        let map = match rng.below(3) {
+            0 => MapChoice::Default,
+            1 => MapChoice::Named(rng.string()),
+            _ => MapChoice::Generated { seed: rng.next(), size: rng.below(10), density: rng.float() },
+        };
+        let messages = vec![
+            Hello { version: rng.below(10) as u32, capabilities: rng.strings() },
+            Login { nick: rng.string() },
+            Authenticate { nick: rng.string(), password: rng.string() },
+            Register { nick: rng.string(), password: rng.string() },
+            Resume { nick: rng.string(), token: rng.string() },
+            Logout,
+            ListGames,
+            ListMaps,
+            CreateGame { name: rng.string(), map, turn_time: if rng.bool() { Some(rng.next()) } else { None } },
+            JoinGame { name: rng.string(), typ: rng.pick(PLAY_TYPES) },
+            LeaveGame,
+            Ready,
+            Unready,
+            SelectColor { color: rng.color() },
+            HoverCell { row: rng.below(100), col: rng.below(100) },
+            HoverNone,
+            Chat { scope: rng.pick(SCOPES), text: rng.string() },
+            NextPhase,
+            FullUp { row: rng.below(100), col: rng.below(100) },
+            Undo,
+            UndoAll,
+            Cell { row: rng.below(100), col: rng.below(100) },
+        ];
+        for message in &messages {
+            match *message {
+                Hello { .. } | Login { .. } | Authenticate { .. } | Register { .. } | Resume { .. } |
+                Logout | ListGames | ListMaps | CreateGame { .. } | JoinGame { .. } | LeaveGame |
+                Ready | Unready | SelectColor { .. } | HoverCell { .. } | HoverNone | Chat { .. } |
+                NextPhase | FullUp { .. } | Undo | UndoAll | Cell { .. } => {}
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn server_message_round_trip() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..500 {
+            for message in server_messages(&mut rng) {
+                let wire = message.to_wire();
+                assert_eq!(ServerMessage::parse(&wire).ok(), Some(message), "wire: {:?}", wire);
+            }
+        }
+    }
+
+    #[test]
+    fn client_message_round_trip() {
+        let mut rng = Rng(0xc11e);
+        for _ in 0..500 {
+            for message in client_messages(&mut rng) {
+                let wire = message.to_wire();
+                assert_eq!(ClientMessage::parse(&wire).ok(), Some(message), "wire: {:?}", wire);
+            }
+        }
+    }
+
+    #[test]
+    fn awkward_names() {
+        for name in &["two words", "say \"hi\"", "a,b", "c:d", "back\\slash", "", " padded ", "none", "-", "+x"] {
+            let name = name.to_string();
+            let message = ServerMessage::GameList { name: name.clone(), player_count: 2 };
+            assert_eq!(ServerMessage::parse(&message.to_wire()).ok(), Some(message));
+            let message = ClientMessage::JoinGame { name: name.clone(), typ: PlayType::Player };
+            assert_eq!(ClientMessage::parse(&message.to_wire()).ok(), Some(message));
+            let message = ClientMessage::Login { nick: name.clone() };
+            assert_eq!(ClientMessage::parse(&message.to_wire()).ok(), Some(message));
+        }
+    }
+
+    #[test]
+    fn maps_named_like_keywords() {
+        for map in &["generate", "map", "time", "generate 1 2 0.5", "time 10", "two words", ""] {
+            for &turn_time in &[None, Some(0), Some(30)] {
+                let message = ClientMessage::CreateGame {
+                    name: String::from("generate"),
+                    map: MapChoice::Named(map.to_string()),
+                    turn_time,
+                };
+                assert_eq!(ClientMessage::parse(&message.to_wire()).ok(), Some(message));
+            }
+        }
+        for &turn_time in &[None, Some(0), Some(30)] {
+            let message = ClientMessage::CreateGame { name: String::from("time"), map: MapChoice::Default, turn_time };
+            assert_eq!(ClientMessage::parse(&message.to_wire()).ok(), Some(message));
+        }
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        let mut rng = Rng(0x9007e);
+        for _ in 0..1000 {
+            let arg = rng.string();
+            assert_eq!(unquote(&quote(&arg)), arg);
+            let line = format!("{},{}:rest", quote(&arg), quote("other"));
+            let separator = find_unquoted(&line, ':').unwrap();
+            let nicks: Vec<String> = split_unquoted(&line[..separator], ',').into_iter().map(unquote).collect();
+            assert_eq!(nicks, vec![arg, String::from("other")]);
+        }
+    }
+
+    #[test]
+    fn log_hides_credentials() {
+        let secret = String::from("hunter2");
+        for message in &[
+            ClientMessage::Authenticate { nick: String::from("a"), password: secret.clone() },
+            ClientMessage::Register { nick: String::from("a"), password: secret.clone() },
+            ClientMessage::Resume { nick: String::from("a"), token: secret.clone() },
+        ] {
+            assert!(!message.to_log().contains(&secret));
+        }
+        assert_eq!(ClientMessage::NextPhase.to_log(), ClientMessage::NextPhase.to_wire());
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert!(ServerMessage::parse("").is_err());
+        assert!(ServerMessage::parse("noSuchCommand").is_err());
+        assert!(ServerMessage::parse("energyLeft").is_err());
+        assert!(ServerMessage::parse("energyLeft many").is_err());
+        assert!(ServerMessage::parse("system \"unterminated").is_err());
+        assert!(ServerMessage::parse("mapLine 0 ##|xx").is_err());
+        assert!(ClientMessage::parse("next turn").is_err());
+        assert!(ClientMessage::parse("1").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use ::*;
+
+pub const REPLAY_DIR: &str = "replays";
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ReplayError::Io(ref e) => write!(f, "{}", e),
+            ReplayError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(e: std::io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+struct Recording {
+    file: std::io::BufWriter<std::fs::File>,
+    start: std::time::Instant,
+}
+
+// Writes a replay file per game. Every line is prefixed with the number of
+// milliseconds since the game started, followed by the line as it is logged:
+// "< nick:message" for received and "> nick1,nick2:message" for routed ones.
+pub struct Recorder {
+    dir: std::path::PathBuf,
+    recordings: HashMap<String, Recording>,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<std::path::Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            recordings: HashMap::new(),
+        }
+    }
+
+    pub fn is_recording(&self, game: &str) -> bool {
+        self.recordings.contains_key(game)
+    }
+
+    pub fn start(&mut self, game: &str) {
+        if self.is_recording(game) {
+            return;
+        }
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let timestamp = now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000;
+        let name: String = game.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let path = self.dir.join(format!("{}-{}.replay", name, timestamp));
+        let file = std::fs::create_dir_all(&self.dir).and_then(|_| std::fs::File::create(&path));
+        match file {
+            Ok(file) => {
+                eprintln!("Recording game {:?} to {:?}", game, path);
+                self.recordings.insert(game.to_owned(), Recording {
+                    file: std::io::BufWriter::new(file),
+                    start: std::time::Instant::now(),
+                });
+            }
+            Err(e) => eprintln!("Failed to record game {:?} to {:?}: {}", game, path, e),
+        }
+    }
+
+    pub fn record(&mut self, game: &str, line: &str) {
+        use std::io::Write;
+        if let Some(recording) = self.recordings.get_mut(game) {
+            let elapsed = recording.start.elapsed();
+            let millis = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+            if let Err(e) = writeln!(recording.file, "{} {}", millis, line) {
+                eprintln!("Failed to write replay of {:?}: {}", game, e);
+            }
+        }
+    }
+
+    pub fn stop(&mut self, game: &str) {
+        use std::io::Write;
+        if let Some(mut recording) = self.recordings.remove(game) {
+            eprintln!("Finished recording game {:?}", game);
+            if let Err(e) = recording.file.flush() {
+                eprintln!("Failed to write replay of {:?}: {}", game, e);
+            }
+        }
+    }
+
+    // Stops recording games that are no longer being played.
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, playing: F) {
+        let stopped: Vec<String> = self.recordings.keys().filter(|game| !playing(game)).cloned().collect();
+        for game in stopped {
+            self.stop(&game);
+        }
+    }
+}
+
+// Reads the messages routed to the players of a recorded game,
+// with the time in seconds at which they were sent.
+pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Vec<(f64, ServerMessage)>, ReplayError> {
+    use std::io::Read;
+    let mut text = String::new();
+    std::fs::File::open(path)?.read_to_string(&mut text)?;
+    let mut messages = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| ReplayError::Parse { line: index + 1, message };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let millis: u64 = match parts.next().unwrap().parse() {
+            Ok(millis) => millis,
+            Err(_) => return Err(error(String::from("missing timestamp"))),
+        };
+        match (parts.next(), parts.next()) {
+            (Some(">"), Some(routed)) => {
+                let separator = find_unquoted(routed, ':').ok_or_else(|| error(String::from("no ':' found")))?;
+                let message = ServerMessage::parse(routed[separator + 1..].trim()).map_err(|e| error(e.to_string()))?;
+                messages.push((millis as f64 / 1000.0, message));
+            }
+            (Some("<"), Some(_)) => {}
+            _ => return Err(error(format!("unexpected line {:?}", line))),
+        }
+    }
+    Ok(messages)
+}
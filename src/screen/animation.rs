@@ -0,0 +1,86 @@
+use ::*;
+
+// Seconds it takes the trolls to walk to the attacked cell at normal speed.
+pub const WALK_TIME: f64 = 0.5;
+// Seconds a battle or capture effect lasts after the trolls arrive.
+pub const EFFECT_TIME: f64 = 0.4;
+
+pub struct Walk {
+    pub from: Vec2<usize>,
+    pub to: Vec2<usize>,
+    pub count: usize,
+    pub color: char,
+}
+
+pub enum Effect {
+    Battle(Vec2<usize>),
+    Capture(Vec2<usize>, char),
+}
+
+// Trolls moving between two successive maps, found by comparing them.
+pub struct Animation {
+    pub old_map: Vec<Vec<Option<GameCell>>>,
+    pub walks: Vec<Walk>,
+    pub effects: Vec<Effect>,
+    pub time: f64,
+}
+
+pub fn cell_at(map: &[Vec<Option<GameCell>>], pos: Vec2<usize>) -> Option<GameCell> {
+    map.get(pos.x).and_then(|line| line.get(pos.y)).and_then(|&cell| cell)
+}
+
+impl Animation {
+    // Trolls that left a cell of the moving player walk to the neighbouring
+    // cells it did not own that changed. There is no animation for upgrades.
+    pub fn diff(old_map: &[Vec<Option<GameCell>>], new_map: &[Vec<Option<GameCell>>], mover: char) -> Option<Self> {
+        let mut walks = Vec::new();
+        let mut effects = Vec::new();
+        for (i, line) in old_map.iter().enumerate() {
+            for (j, &old_cell) in line.iter().enumerate() {
+                let from = vec2(i, j);
+                let count = match (old_cell, cell_at(new_map, from)) {
+                    (Some(GameCell::Populated { count: old_count, owner: old_owner }),
+                        Some(GameCell::Populated { count: new_count, owner: new_owner }))
+                    if old_owner == mover && new_owner == mover && new_count < old_count => old_count - new_count,
+                    _ => continue,
+                };
+                for to in neighbours(from) {
+                    let (before, after) = match (cell_at(old_map, to), cell_at(new_map, to)) {
+                        (Some(before), Some(after)) if before != after => (before, after),
+                        _ => continue,
+                    };
+                    if let GameCell::Populated { owner, .. } = before {
+                        if owner == mover {
+                            continue;
+                        }
+                        effects.push(Effect::Battle(to));
+                    }
+                    if let GameCell::Populated { owner, .. } = after {
+                        if owner == mover {
+                            effects.push(Effect::Capture(to, mover));
+                        }
+                    }
+                    walks.push(Walk { from, to, count, color: mover });
+                }
+            }
+        }
+        if walks.is_empty() {
+            return None;
+        }
+        Some(Self {
+            old_map: old_map.to_vec(),
+            walks,
+            effects,
+            time: 0.0,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.time >= WALK_TIME + EFFECT_TIME
+    }
+
+    // Whether the trolls are still on their way to this cell, so it should look like it did before.
+    pub fn is_arriving(&self, pos: Vec2<usize>) -> bool {
+        self.time < WALK_TIME && self.walks.iter().any(|walk| walk.to == pos)
+    }
+}
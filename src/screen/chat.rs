@@ -0,0 +1,144 @@
+use ::*;
+
+const CHAT_HISTORY: usize = 100;
+const CHAT_LINES: usize = 8;
+const CHAT_SIZE: f32 = 2.5;
+const CHAT_OFFSET: f32 = 1.0;
+const CHAT_WIDTH: f32 = 0.4;
+
+// Prefix of a message sent only to the other spectators.
+const SPECTATORS_PREFIX: &str = "/s ";
+
+struct ChatLine {
+    scope: ChatScope,
+    nick: String,
+    text: String,
+}
+
+// Chat shown in the bottom left corner of a screen. Tab starts typing,
+// Enter sends, Escape stops typing and Up/Down scroll through older messages.
+pub struct ChatPanel {
+    font: codevisual::Font,
+    menu: MenuScreen,
+    scope: ChatScope,
+    lines: std::collections::VecDeque<ChatLine>,
+    input: String,
+    focused: bool,
+    scroll: usize,
+}
+
+impl ChatPanel {
+    // Messages are sent to the given scope, and to the spectators if prefixed with SPECTATORS_PREFIX.
+    pub fn new(app: &Rc<codevisual::App>, scope: ChatScope) -> Self {
+        Self {
+            font: codevisual::Font::new(app.ugli_context(), (include_bytes!("font.ttf") as &[u8]).to_owned()),
+            menu: MenuScreen::new(app, Vec::new()),
+            scope,
+            lines: std::collections::VecDeque::new(),
+            input: String::new(),
+            focused: false,
+            scroll: 0,
+        }
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    pub fn add(&mut self, scope: ChatScope, nick: String, text: String) {
+        self.lines.push_back(ChatLine { scope, nick, text });
+        if self.lines.len() > CHAT_HISTORY {
+            self.lines.pop_front();
+        }
+        if self.scroll > 0 {
+            self.scroll = std::cmp::min(self.scroll + 1, self.lines.len().saturating_sub(1));
+        }
+    }
+
+    fn send(&mut self, sender: &mut connection::Sender) {
+        let input = mem::replace(&mut self.input, String::new());
+        let (scope, text) = if self.scope == ChatScope::Game && input.starts_with(SPECTATORS_PREFIX) {
+            (ChatScope::Spectators, &input[SPECTATORS_PREFIX.len()..])
+        } else {
+            (self.scope, input.as_str())
+        };
+        if !text.trim().is_empty() {
+            sender.send(ClientMessage::Chat { scope, text: text.to_owned() });
+        }
+    }
+
+    // Returns true if the event was used by the chat.
+    pub fn handle(&mut self, event: &codevisual::Event, sender: &mut connection::Sender) -> bool {
+        let key = match *event {
+            codevisual::Event::KeyDown { key } => key,
+            _ => return false,
+        };
+        if let codevisual::Key::Tab = key {
+            self.focused = !self.focused;
+            return true;
+        }
+        if !self.focused {
+            return false;
+        }
+        match key {
+            codevisual::Key::Enter => self.send(sender),
+            codevisual::Key::Escape => self.focused = false,
+            codevisual::Key::Backspace => {
+                self.input.pop();
+            }
+            codevisual::Key::Up => {
+                self.scroll = std::cmp::min(self.scroll + 1, self.lines.len().saturating_sub(1));
+            }
+            codevisual::Key::Down => {
+                self.scroll = self.scroll.saturating_sub(1);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    // Returns true if the chat is focused and so took the character.
+    pub fn type_char(&mut self, c: char) -> bool {
+        if !self.focused {
+            return false;
+        }
+        if self.input.chars().count() < MAX_CHAT_LENGTH {
+            self.input.push(c);
+        }
+        true
+    }
+
+    // Draws the panel with its bottom edge `bottom` units (hundredths of the height) above the bottom of the screen.
+    pub fn draw(&self, framebuffer: &mut ugli::Framebuffer, bottom: f32, player_colors: &HashMap<String, char>) {
+        let framebuffer_size = framebuffer.get_size();
+        let framebuffer_size = vec2(framebuffer_size.x as f32, framebuffer_size.y as f32);
+        let unit = framebuffer_size.y / 100.0;
+        let line_height = CHAT_SIZE + CHAT_OFFSET;
+        let shown = std::cmp::min(CHAT_LINES, self.lines.len());
+        let height = (shown + 1) as f32 * line_height + CHAT_OFFSET;
+        self.menu.draw_rect(framebuffer,
+                            vec2(-1.0, bottom / 50.0 - 1.0),
+                            vec2(CHAT_WIDTH * 2.0 - 1.0, (bottom + height) / 50.0 - 1.0),
+                            Color::rgb(0.05, 0.05, 0.1));
+        let x = CHAT_OFFSET * unit;
+        let mut y = bottom + CHAT_OFFSET;
+        let input = if self.focused {
+            format!("> {}_", self.input)
+        } else {
+            String::from("tab to chat")
+        };
+        self.font.draw_aligned(framebuffer, &input, vec2(x, y * unit), 0.0, CHAT_SIZE * unit,
+                               if self.focused { Color::WHITE } else { Color::rgb(0.5, 0.5, 0.5) });
+        for line in self.lines.iter().rev().skip(self.scroll).take(shown) {
+            y += line_height;
+            let name = match line.scope {
+                ChatScope::Spectators => format!("[spectators] {}: ", line.nick),
+                _ => format!("{}: ", line.nick),
+            };
+            let color = player_colors.get(&line.nick).map_or(Color::rgb(0.7, 0.7, 0.7), |&color| player_color(color));
+            self.font.draw_aligned(framebuffer, &name, vec2(x, y * unit), 0.0, CHAT_SIZE * unit, color);
+            let name_width = self.font.measure(&name, CHAT_SIZE * unit).map_or(0.0, |rect| rect.width());
+            self.font.draw_aligned(framebuffer, &line.text, vec2(x + name_width, y * unit), 0.0, CHAT_SIZE * unit, Color::WHITE);
+        }
+    }
+}
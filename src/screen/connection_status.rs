@@ -0,0 +1,77 @@
+use ::*;
+
+const STATUS_INDEX: usize = 3;
+const RETRY_INDEX: usize = 4;
+const CHANGE_SERVER_INDEX: usize = 5;
+
+pub enum StatusAction {
+    Retry,
+    ChangeServer,
+}
+
+// Shown instead of the current screen while not connected to the server.
+pub struct ConnectionStatus {
+    menu: MenuScreen,
+}
+
+impl ConnectionStatus {
+    pub fn new(app: &Rc<codevisual::App>) -> Self {
+        Self {
+            menu: MenuScreen::new(app, vec![
+                MenuSection {
+                    text: String::from("TroLL InvaSioN"),
+                    size: 20.0,
+                    color: Color::rgb(0.8, 0.8, 1.0),
+                    back_color: Color::BLACK,
+                    hover_color: None,
+                },
+                MenuSection::new_empty(1.0, Color::rgb(0.05, 0.05, 0.05)),
+                MenuSection::new_empty(10.0, Color::BLACK),
+                MenuSection {
+                    text: String::new(),
+                    size: 5.0,
+                    color: Color::WHITE,
+                    back_color: Color::BLACK,
+                    hover_color: None,
+                },
+                MenuSection {
+                    text: String::from("retry"),
+                    size: 10.0,
+                    color: Color::WHITE,
+                    back_color: Color::rgb(0.3, 0.3, 0.3),
+                    hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
+                },
+                MenuSection {
+                    text: String::from("change server"),
+                    size: 10.0,
+                    color: Color::WHITE,
+                    back_color: Color::rgb(0.3, 0.3, 0.3),
+                    hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
+                }]),
+        }
+    }
+
+    pub fn draw(&mut self, framebuffer: &mut ugli::Framebuffer, state: connection::ConnectionState) {
+        use connection::ConnectionState::*;
+        let server = format!("{}:{}", HOST.lock().unwrap(), PORT.lock().unwrap());
+        self.menu.sections[STATUS_INDEX].text = match state {
+            Connecting | Connected => format!("connecting to {}", server),
+            Lost => String::from("connection lost, reconnecting"),
+            Retrying { attempt, next_attempt } => {
+                let now = std::time::Instant::now();
+                let seconds = if next_attempt > now { (next_attempt - now).as_secs() + 1 } else { 0 };
+                format!("could not connect, retrying in {}s (attempt {})", seconds, attempt)
+            }
+            Failed => format!("could not connect to {}", server),
+        };
+        self.menu.draw(framebuffer);
+    }
+
+    pub fn handle(&mut self, event: codevisual::Event) -> Option<StatusAction> {
+        match self.menu.handle(event) {
+            Some(RETRY_INDEX) => Some(StatusAction::Retry),
+            Some(CHANGE_SERVER_INDEX) => Some(StatusAction::ChangeServer),
+            _ => None,
+        }
+    }
+}
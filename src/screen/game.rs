@@ -15,12 +15,17 @@ pub struct Game {
     map: Vec<Vec<Option<GameCell>>>,
     next_frame_time: f64,
     next_map: Vec<Vec<Option<GameCell>>>,
-    map_queue: std::collections::VecDeque<Vec<Vec<Option<GameCell>>>>,
+    // Maps to show next, with the color of the player who made the move.
+    map_queue: std::collections::VecDeque<(Vec<Vec<Option<GameCell>>>, Option<char>)>,
+    animation: Option<Animation>,
+    animation_speed: f64,
     material: codevisual::Material,
     app: Rc<codevisual::App>,
     current_player: String,
     matrix: Cell<Mat4<f32>>,
     energy_left: Option<usize>,
+    pending_upgrades: usize,
+    turn_time_left: Option<f64>,
     selected_cell: Option<Vec2<usize>>,
     hovered_cell: Option<Vec2<usize>>,
     camera_pos: Vec2<f32>,
@@ -31,7 +36,10 @@ pub struct Game {
     dragging: bool,
     start_drag: Option<Vec2>,
     randoms: Vec<Vec2<f32>>,
+    show_minimap: bool,
+    minimap_drag: bool,
     menu: MenuScreen,
+    chat: ChatPanel,
 }
 
 impl Screen for Game {
@@ -40,6 +48,9 @@ impl Screen for Game {
             Event::Event(event) => self.handle_event(event),
             Event::Draw(framebuffer) => self.draw(framebuffer),
             Event::Update(delta_time) => self.update(delta_time),
+            Event::Text(c) => {
+                self.chat.type_char(c);
+            }
             Event::Message(message) => { return self.handle_message(message); }
         }
         None
@@ -58,12 +69,42 @@ pub fn player_color(color: char) -> Color {
     }
 }
 
+fn cell_center(pos: Vec2<usize>) -> Vec2<f32> {
+    vec2((pos.y as f32 + 0.5) / 3.0.sqrt(), pos.x as f32 + 0.5)
+}
+
+// Keyboard cursor moves: Left/Right along the row, Up/Down and Q/E along the diagonals.
+fn cursor_direction(key: codevisual::Key) -> Option<(isize, isize)> {
+    match key {
+        codevisual::Key::Left => Some((0, -2)),
+        codevisual::Key::Right => Some((0, 2)),
+        codevisual::Key::Up => Some((1, 1)),
+        codevisual::Key::Down => Some((-1, -1)),
+        codevisual::Key::Q => Some((1, -1)),
+        codevisual::Key::E => Some((-1, 1)),
+        _ => None,
+    }
+}
+
+// The camera follows the keyboard cursor when it gets this close to the edge of the screen, in -1..1 coordinates.
+const CURSOR_MARGIN: f32 = 0.7;
+
 const LEAVE_SIZE: f32 = 2.0;
 const LEAVE_OFFSET: f32 = 2.0;
 
 const STATUS_SIZE: f32 = 4.0;
 const STATUS_OFFSET: f32 = 2.0;
 
+// The chat sits just above the status bar.
+const CHAT_BOTTOM: f32 = 10.0;
+
+// The minimap is in the top right corner under the leave button, this big along the longer side of the map.
+const MINIMAP_SIZE: f32 = 25.0;
+const MINIMAP_OFFSET: f32 = 2.0;
+
+// The turn countdown turns red with this many seconds left.
+const TURN_TIME_WARNING: f64 = 10.0;
+
 impl Game {
     pub fn new(app: &Rc<codevisual::App>, nick: String, sender: connection::Sender) -> Self {
         Self {
@@ -75,7 +116,10 @@ impl Game {
             app: app.clone(),
             nick,
             menu: MenuScreen::new(app, vec![]),
+            chat: ChatPanel::new(app, ChatScope::Game),
             randoms: Vec::new(),
+            show_minimap: true,
+            minimap_drag: false,
             matrix: Cell::new(Mat4::identity()),
             current_player: String::new(),
             map: Vec::new(),
@@ -98,16 +142,26 @@ impl Game {
                 Vertex { a_pos: vec2(1.0, 2.0) },
                 Vertex { a_pos: vec2(-1.0, 2.0) }, ]),
             energy_left: None,
+            pending_upgrades: 0,
+            turn_time_left: None,
             sender,
             font: codevisual::Font::new(app.ugli_context(), (include_bytes!("font.ttf") as &[u8]).to_owned()),
             player_colors: HashMap::new(),
             player_hovers: HashMap::new(),
             map_queue: std::collections::VecDeque::new(),
+            animation: None,
+            animation_speed: *ANIMATION_SPEED.lock().unwrap(),
             camera_pos: vec2(0.0, 0.0),
             camera_dist: 1.5,
         }
     }
 
+    // Keeps the messages from the game lobby.
+    pub fn with_chat(mut self, chat: ChatPanel) -> Self {
+        self.chat = chat;
+        self
+    }
+
     fn handle_message(&mut self, message: ServerMessage) -> Option<Box<Screen>> {
         use ServerMessage::*;
         match message {
@@ -115,10 +169,12 @@ impl Game {
                 self.next_map.push(line);
             }
             EndMap => {
-                self.map_queue.push_back(mem::replace(&mut self.next_map, Vec::new()));
+                let mover = self.player_colors.get(&self.current_player).cloned();
+                self.map_queue.push_back((mem::replace(&mut self.next_map, Vec::new()), mover));
             }
             UpgradePhase => {
                 self.selected_cell = None;
+                self.pending_upgrades = 0;
             }
             SelectCell { row, col } => {
                 self.selected_cell = Some(vec2(row, col));
@@ -126,13 +182,18 @@ impl Game {
             DeselectCell => {
                 self.selected_cell = None;
             }
-            Turn { nick } => {
+            Turn { nick, time_left } => {
                 self.current_player = nick;
                 self.energy_left = None;
+                self.pending_upgrades = 0;
+                self.turn_time_left = time_left;
             }
             EnergyLeft(energy) => {
                 self.energy_left = Some(energy);
             }
+            PendingUpgrades(count) => {
+                self.pending_upgrades = count;
+            }
             GameFinish { winner } => {
                 return Some(Box::new(WinnerScreen::new(
                     &self.app, self.nick.clone(),
@@ -159,20 +220,44 @@ impl Game {
             CanMove { cells } => {
                 self.can_moves = cells;
             }
+            Chat { scope, nick, text } => {
+                self.chat.add(scope, nick, text);
+            }
+            Rejected { reason } => {
+                return Some(NicknameScreen::rejected(&self.app, reason));
+            }
             _ => {}
         }
         None
     }
 
     fn update(&mut self, delta_time: f64) {
+        if let Some(ref mut time_left) = self.turn_time_left {
+            *time_left = (*time_left - delta_time).max(0.0);
+        }
+        if let Some(ref mut animation) = self.animation {
+            animation.time += delta_time * self.animation_speed;
+        }
+        if self.animation.as_ref().map_or(false, |animation| animation.is_finished()) {
+            self.animation = None;
+        }
         self.next_frame_time -= delta_time;
         if self.next_frame_time < 0.0 {
-            if let Some(map) = self.map_queue.pop_front() {
-                if self.map.is_empty() {}
+            if let Some((map, mover)) = self.map_queue.pop_front() {
+                self.animation = match mover {
+                    Some(mover) if self.animation_speed > 0.0 && !self.map.is_empty() => Animation::diff(&self.map, &map, mover),
+                    _ => None,
+                };
+                self.next_frame_time = match self.animation {
+                    Some(_) => (WALK_TIME + EFFECT_TIME) / self.animation_speed,
+                    None => 0.1,
+                };
                 self.map = map;
-                self.next_frame_time = 0.1;
             }
         }
+        if self.chat.is_focused() {
+            return;
+        }
         let mut dv: Vec2<f32> = vec2(0.0, 0.0);
         if self.app.window().is_key_pressed(codevisual::Key::W) {
             dv.y += 1.0;
@@ -194,8 +279,12 @@ impl Game {
         Mat4::perspective(std::f32::consts::PI / 5.0, aspect, 0.1, 100.0)
     }
 
+    fn map_size(&self) -> (f32, f32) {
+        (self.map[0].len() as f32 / 3.0.sqrt(), self.map.len() as f32)
+    }
+
     fn view_matrix(&self) -> Mat4<f32> {
-        let (width, height) = (self.map[0].len() as f32 / 3.0.sqrt(), self.map.len() as f32);
+        let (width, height) = self.map_size();
         Mat4::translate(vec3(0.0, 0.0, -self.camera_dist * 2.5)) *
             Mat4::rotate_x(-0.5) *
             Mat4::translate(-self.camera_pos.extend(0.0)) *
@@ -259,10 +348,17 @@ impl Game {
                     }
                 }
             }
+            if let Some(ref animation) = self.animation {
+                self.draw_effects(framebuffer, animation);
+            }
             for (i, line) in self.map.iter().enumerate() {
                 for (j, cell) in line.iter().enumerate() {
                     let center = vec2((j as f32 + 0.5) / 3.0.sqrt(), i as f32 + 0.5);
-                    if let Some(cell) = *cell {
+                    let cell = match self.animation {
+                        Some(ref animation) if animation.is_arriving(vec2(i, j)) => cell_at(&animation.old_map, vec2(i, j)),
+                        _ => *cell,
+                    };
+                    if let Some(cell) = cell {
                         if let GameCell::Populated { count, owner } = cell {
                             for index in 0..count {
                                 let pos = center + Vec2::rotated(vec2(0.3, 0.0), (index as f32 / count as f32) * 2.0 * std::f32::consts::PI);
@@ -281,6 +377,9 @@ impl Game {
                     }
                 }
             }
+            if let Some(ref animation) = self.animation {
+                self.draw_walks(framebuffer, animation);
+            }
         }
 
         let framebuffer_size = framebuffer.get_size();
@@ -304,22 +403,130 @@ impl Game {
         if !self.current_player.is_empty() {
             let current_status = format!("{}'s turn: {}", self.current_player, match self.energy_left {
                 None => String::from("Attack phase"),
-                Some(energy) => format!("Upgrade phase ({} energy left)", energy),
+                Some(energy) if self.pending_upgrades == 0 => format!("Upgrade phase ({} energy left)", energy),
+                Some(energy) => format!("Upgrade phase ({} energy left, {} to undo: Z/X)", energy, self.pending_upgrades),
             });
-            if self.status_hover() {
-                self.font.draw_aligned(
-                    framebuffer,
-                    if self.energy_left.is_none() { "next phase" } else { "end turn" },
-                    vec2(framebuffer_size.x as f32 / 2.0, STATUS_OFFSET * unit),
-                    0.5, STATUS_SIZE * unit, Color::WHITE);
+            let (status_text, status_color) = if self.status_hover() {
+                let action = if self.energy_left.is_none() { "next phase" } else { "end turn" };
+                (String::from(action), Color::WHITE)
             } else {
+                (current_status, player_color(self.player_colors[&self.current_player]))
+            };
+            self.font.draw_aligned(
+                framebuffer,
+                &status_text,
+                vec2(framebuffer_size.x as f32 / 2.0, STATUS_OFFSET * unit),
+                0.5, STATUS_SIZE * unit, status_color);
+            if let Some(time_left) = self.turn_time_left {
+                let status_width = self.font.measure(&status_text, STATUS_SIZE * unit).map_or(0.0, |rect| rect.width());
                 self.font.draw_aligned(
                     framebuffer,
-                    &current_status,
-                    vec2(framebuffer_size.x as f32 / 2.0, STATUS_OFFSET * unit),
-                    0.5, STATUS_SIZE * unit, player_color(self.player_colors[&self.current_player]));
+                    &format!("{}s", time_left.ceil() as u64),
+                    vec2(framebuffer_size.x as f32 / 2.0 + status_width / 2.0 + STATUS_SIZE * unit, STATUS_OFFSET * unit),
+                    0.0, STATUS_SIZE * unit,
+                    if time_left <= TURN_TIME_WARNING { Color::RED } else { Color::WHITE });
+            }
+        }
+
+        self.draw_minimap(framebuffer);
+        self.chat.draw(framebuffer, CHAT_BOTTOM, &self.player_colors);
+    }
+
+    // Bottom left corner of the minimap in pixels from the bottom left of the window, and pixels per map unit.
+    fn minimap_layout(&self) -> Option<(Vec2<f32>, f32)> {
+        if !self.show_minimap || self.map.is_empty() {
+            return None;
+        }
+        let window_size = self.app.window().get_size();
+        let unit = window_size.y as f32 / 100.0;
+        let (width, height) = self.map_size();
+        let scale = MINIMAP_SIZE * unit / max(width, height);
+        let top = LEAVE_OFFSET * 2.0 + LEAVE_SIZE + MINIMAP_OFFSET;
+        Some((vec2(window_size.x as f32 - MINIMAP_OFFSET * unit - width * scale,
+                   window_size.y as f32 - top * unit - height * scale), scale))
+    }
+
+    // Map point under the cursor, if the minimap is shown. It may lie outside the map.
+    fn minimap_point(&self, pos: Vec2) -> Option<Vec2<f32>> {
+        self.minimap_layout().map(|(origin, scale)| {
+            let window_size = self.app.window().get_size();
+            (vec2(pos.x as f32, window_size.y as f32 - pos.y as f32) - origin) / scale
+        })
+    }
+
+    fn map_contains(&self, point: Vec2<f32>) -> bool {
+        let (width, height) = self.map_size();
+        point.x >= 0.0 && point.y >= 0.0 && point.x <= width && point.y <= height
+    }
+
+    // Moves the camera to look at the given map point.
+    fn look_at(&mut self, point: Vec2<f32>) {
+        let (width, height) = self.map_size();
+        let point = vec2(clamp(point.x, 0.0, width), clamp(point.y, 0.0, height));
+        self.camera_pos = (point - vec2(width, height) / 2.0) * 2.0 / max(width, height);
+    }
+
+    // Map point seen at the given screen position in -1..1 coordinates.
+    fn unproject(&self, pos: Vec2<f32>) -> Option<Vec2<f32>> {
+        let matrix = self.matrix.get();
+        let ex = matrix * vec2(1.0, 0.0).extend(0.0).extend(0.0);
+        let ey = matrix * vec2(0.0, 1.0).extend(0.0).extend(0.0);
+        let origin = matrix * vec2(0.0, 0.0).extend(0.0).extend(1.0);
+        let (a, b, c) = (ex.x - pos.x * ex.w, ey.x - pos.x * ey.w, origin.x - pos.x * origin.w);
+        let (d, e, f) = (ex.y - pos.y * ex.w, ey.y - pos.y * ey.w, origin.y - pos.y * origin.w);
+        let det = a * e - b * d;
+        if det.abs() < 1e-6 {
+            return None;
+        }
+        Some(vec2((b * f - c * e) / det, (c * d - a * f) / det))
+    }
+
+    fn draw_minimap(&self, framebuffer: &mut ugli::Framebuffer) {
+        let (origin, scale) = match self.minimap_layout() {
+            Some(layout) => layout,
+            None => return,
+        };
+        let framebuffer_size = framebuffer.get_size();
+        let framebuffer_size = vec2(framebuffer_size.x as f32, framebuffer_size.y as f32);
+        let to_screen = |point: Vec2<f32>| {
+            let pos = origin + point * scale;
+            vec2(pos.x / framebuffer_size.x * 2.0 - 1.0, pos.y / framebuffer_size.y * 2.0 - 1.0)
+        };
+        let (width, height) = self.map_size();
+        self.menu.draw_rect(framebuffer, to_screen(vec2(0.0, 0.0)), to_screen(vec2(width, height)), Color::rgb(0.05, 0.05, 0.05));
+        let cell_size = vec2(1.0 / 3.0.sqrt(), 0.5) * 0.9;
+        for (i, line) in self.map.iter().enumerate() {
+            for (j, cell) in line.iter().enumerate() {
+                let color = match *cell {
+                    Some(GameCell::Populated { owner, .. }) => player_color(owner),
+                    Some(GameCell::Empty) => Color::rgb(0.5, 0.4, 0.3),
+                    None if (i + j) % 2 == 0 => Color::rgb(0.1, 0.4, 0.1),
+                    None => continue,
+                };
+                let center = cell_center(vec2(i, j));
+                self.menu.draw_rect(framebuffer, to_screen(center - cell_size), to_screen(center + cell_size), color);
             }
         }
+
+        let corners: Vec<Vec2<f32>> = [vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)].iter()
+            .filter_map(|&corner| self.unproject(corner))
+            .collect();
+        if corners.len() < 4 {
+            return;
+        }
+        let lower = vec2(corners.iter().map(|p| p.x).fold(width, f32::min).max(0.0),
+                         corners.iter().map(|p| p.y).fold(height, f32::min).max(0.0));
+        let upper = vec2(corners.iter().map(|p| p.x).fold(0.0, f32::max).min(width),
+                         corners.iter().map(|p| p.y).fold(0.0, f32::max).min(height));
+        if lower.x >= upper.x || lower.y >= upper.y {
+            return;
+        }
+        let border = 2.0 / scale;
+        let color = Color::WHITE;
+        self.menu.draw_rect(framebuffer, to_screen(lower), to_screen(vec2(upper.x, lower.y + border)), color);
+        self.menu.draw_rect(framebuffer, to_screen(vec2(lower.x, upper.y - border)), to_screen(upper), color);
+        self.menu.draw_rect(framebuffer, to_screen(lower), to_screen(vec2(lower.x + border, upper.y)), color);
+        self.menu.draw_rect(framebuffer, to_screen(vec2(upper.x - border, lower.y)), to_screen(upper), color);
     }
 
     fn status_hover(&self) -> bool {
@@ -341,33 +548,49 @@ impl Game {
     }
 
     fn handle_event(&mut self, event: codevisual::Event) {
+        if self.chat.handle(&event, &mut self.sender) {
+            return;
+        }
         let window_size = self.app.window().get_size();
         match event {
             codevisual::Event::MouseUp { button: codevisual::MouseButton::Left, position: pos } => {
                 self.start_drag = None;
-                if self.dragging {
+                if self.minimap_drag {
+                    self.minimap_drag = false;
+                } else if self.dragging {
                     self.dragging = false;
                 } else if self.leave_rect_hover() {
-                    self.sender.send("leaveGame");
+                    self.sender.send(ClientMessage::LeaveGame);
                 } else if self.status_hover() {
-                    self.sender.send("next phase");
+                    self.sender.send(ClientMessage::NextPhase);
                 } else if !self.map.is_empty() {
                     if let Some(Vec2 { x, y }) = self.find_pos(vec2(pos.x as f32, pos.y as f32)) {
-                        self.sender.send(format!("{} {}", x, y));
+                        self.sender.send(ClientMessage::Cell { row: x, col: y });
                     }
                 }
             }
             codevisual::Event::MouseDown { button: codevisual::MouseButton::Left, position: pos } => {
-                self.start_drag = Some(pos);
+                match self.minimap_point(pos) {
+                    Some(point) if self.map_contains(point) => {
+                        self.minimap_drag = true;
+                        self.look_at(point);
+                    }
+                    _ => self.start_drag = Some(pos),
+                }
             }
             codevisual::Event::MouseDown { button: codevisual::MouseButton::Right, position: pos } => {
                 if let Some(Vec2 { x, y }) = self.find_pos(vec2(pos.x as f32, pos.y as f32)) {
-                    self.sender.send(format!("fullUp {} {}", x, y));
+                    self.sender.send(ClientMessage::FullUp { row: x, col: y });
                 }
             }
             codevisual::Event::MouseMove { position: pos } => {
                 let mut captured = false;
-                if let Some(start) = self.start_drag {
+                if self.minimap_drag {
+                    if let Some(point) = self.minimap_point(pos) {
+                        self.look_at(point);
+                    }
+                    captured = true;
+                } else if let Some(start) = self.start_drag {
                     if self.dragging {
                         let dv = (pos - start) / self.app.window().get_size().y as f64 * 2.0 * self.camera_dist as f64;
                         self.camera_pos.x -= dv.x as f32;
@@ -381,21 +604,100 @@ impl Game {
                 }
                 if !captured {
                     let cell = self.find_pos(vec2(pos.x as f32, pos.y as f32));
-                    if self.hovered_cell != cell {
-                        self.sender.send(match cell {
-                            None => format!("hover none"),
-                            Some(pos) => format!("hover {} {}", pos.x, pos.y),
-                        });
-                        self.hovered_cell = cell;
-                    }
+                    self.hover(cell);
                 }
             }
+            codevisual::Event::KeyDown { key } => self.handle_key(key),
             codevisual::Event::Wheel { delta } => {
                 self.camera_dist = clamp(self.camera_dist * (1.0 - delta as f32 / 1000.0), 0.3, 3.0);
             }
             _ => {}
         }
     }
+    fn handle_key(&mut self, key: codevisual::Key) {
+        if let Some(direction) = cursor_direction(key) {
+            self.move_cursor(direction);
+            return;
+        }
+        match key {
+            codevisual::Key::Enter => {
+                if let Some(pos) = self.hovered_cell {
+                    self.sender.send(ClientMessage::Cell { row: pos.x, col: pos.y });
+                }
+            }
+            codevisual::Key::U => {
+                if let Some(pos) = self.hovered_cell {
+                    self.sender.send(ClientMessage::FullUp { row: pos.x, col: pos.y });
+                }
+            }
+            codevisual::Key::Space => self.sender.send(ClientMessage::NextPhase),
+            codevisual::Key::Escape => {
+                // Clicking the selected cell again deselects it.
+                if let Some(pos) = self.selected_cell {
+                    self.sender.send(ClientMessage::Cell { row: pos.x, col: pos.y });
+                }
+            }
+            codevisual::Key::M => {
+                self.show_minimap = !self.show_minimap;
+                self.minimap_drag = false;
+            }
+            codevisual::Key::Z => self.sender.send(ClientMessage::Undo),
+            codevisual::Key::X => self.sender.send(ClientMessage::UndoAll),
+            _ => {}
+        }
+    }
+    fn hover(&mut self, cell: Option<Vec2<usize>>) {
+        if self.hovered_cell != cell {
+            self.sender.send(match cell {
+                None => ClientMessage::HoverNone,
+                Some(pos) => ClientMessage::HoverCell { row: pos.x, col: pos.y },
+            });
+            self.hovered_cell = cell;
+        }
+    }
+    // Moves the hovered cell to the neighbouring cell in the given direction, if there is one.
+    // Starts from the selected cell or one of the player's cells if nothing is hovered.
+    fn move_cursor(&mut self, (row_delta, col_delta): (isize, isize)) {
+        let start = match self.hovered_cell.or(self.selected_cell) {
+            Some(pos) => pos,
+            None => {
+                if let Some(pos) = self.first_cell() {
+                    self.set_cursor(pos);
+                }
+                return;
+            }
+        };
+        let (row, col) = (start.x as isize + row_delta, start.y as isize + col_delta);
+        if row < 0 || col < 0 {
+            return;
+        }
+        let pos = vec2(row as usize, col as usize);
+        if cell_at(&self.map, pos).is_some() {
+            self.set_cursor(pos);
+        }
+    }
+    fn set_cursor(&mut self, pos: Vec2<usize>) {
+        self.hover(Some(pos));
+        let screen_pos = self.matrix.get() * cell_center(pos).extend(0.0).extend(1.0);
+        let screen_pos = vec2(screen_pos.x / screen_pos.w, screen_pos.y / screen_pos.w);
+        if screen_pos.x.abs() > CURSOR_MARGIN || screen_pos.y.abs() > CURSOR_MARGIN {
+            self.look_at(cell_center(pos));
+        }
+    }
+    fn first_cell(&self) -> Option<Vec2<usize>> {
+        let color = self.player_colors.get(&self.nick).cloned();
+        let mut first = None;
+        for (i, line) in self.map.iter().enumerate() {
+            for (j, cell) in line.iter().enumerate() {
+                match *cell {
+                    Some(GameCell::Populated { owner, .. }) if Some(owner) == color => return Some(vec2(i, j)),
+                    Some(_) if first.is_none() => first = Some(vec2(i, j)),
+                    _ => {}
+                }
+            }
+        }
+        first
+    }
     fn find_pos(&self, pos: Vec2<f32>) -> Option<Vec2<usize>> {
         let pos = vec2((pos.x * 2.0 / self.app.window().get_size().x as f32 - 1.0),
                        (1.0 - pos.y * 2.0 / self.app.window().get_size().y as f32));
@@ -484,6 +786,37 @@ impl Game {
                        ..Default::default()
                    });
     }
+    fn draw_walks(&self, framebuffer: &mut ugli::Framebuffer, animation: &Animation) {
+        if animation.time >= WALK_TIME {
+            return;
+        }
+        let t = (animation.time / WALK_TIME) as f32;
+        let t = t * t * (3.0 - 2.0 * t);
+        for walk in &animation.walks {
+            let from = cell_center(walk.from);
+            let to = cell_center(walk.to);
+            for index in 0..walk.count {
+                let offset = Vec2::rotated(vec2(0.3, 0.0), (index as f32 / walk.count as f32) * 2.0 * std::f32::consts::PI);
+                self.draw_troll(framebuffer, from + (to - from) * t + offset, player_color(walk.color));
+            }
+        }
+    }
+    fn draw_effects(&self, framebuffer: &mut ugli::Framebuffer, animation: &Animation) {
+        if animation.time < WALK_TIME {
+            return;
+        }
+        let t = ((animation.time - WALK_TIME) / EFFECT_TIME).min(1.0) as f32;
+        for effect in &animation.effects {
+            match *effect {
+                Effect::Battle(pos) => {
+                    self.hex(framebuffer, cell_center(pos), 2.0 / 3.0 * (1.0 + t / 2.0), Color::rgba(1.0, 1.0, 1.0, 1.0 - t));
+                }
+                Effect::Capture(pos, color) => {
+                    self.hex(framebuffer, cell_center(pos), 2.0 / 3.0, Color { alpha: 1.0 - t, ..player_color(color) });
+                }
+            }
+        }
+    }
     fn draw_troll(&self, framebuffer: &mut ugli::Framebuffer, pos: Vec2<f32>, color: Color) {
         self.draw_sprite(framebuffer, pos, color, &resources().troll_texture, vec2(1.0, 1.0), false);
     }
@@ -9,6 +9,7 @@ pub struct GameLobby {
     players: BTreeMap<String, bool>,
     player_colors: HashMap<String, char>,
     play_type: PlayType,
+    chat: ChatPanel,
 }
 
 const READY_INDEX: usize = 5;
@@ -59,6 +60,7 @@ impl Screen for GameLobby {
                         pos.x += size.x * 2.0;
                     }
                 }
+                self.chat.draw(framebuffer, 0.0, &self.player_colors);
             }
             Event::Message(message) => {
                 match message {
@@ -70,7 +72,8 @@ impl Screen for GameLobby {
                         }
                     }
                     ServerMessage::GameStart => {
-                        return Some(Box::new(Game::new(&self.app, self.nick.clone(), self.sender.clone())));
+                        let chat = mem::replace(&mut self.chat, ChatPanel::new(&self.app, ChatScope::Game));
+                        return Some(Box::new(Game::new(&self.app, self.nick.clone(), self.sender.clone()).with_chat(chat)));
                     }
                     ServerMessage::GameLeft { nick } => {
                         if nick == self.nick {
@@ -83,17 +86,29 @@ impl Screen for GameLobby {
                     ServerMessage::PlayerColor { nick, color } => {
                         self.player_colors.insert(nick, color);
                     }
+                    ServerMessage::Chat { scope, nick, text } => {
+                        self.chat.add(scope, nick, text);
+                    }
+                    ServerMessage::Rejected { reason } => {
+                        return Some(NicknameScreen::rejected(&self.app, reason));
+                    }
                     _ => {}
                 }
             }
+            Event::Text(c) => {
+                self.chat.type_char(c);
+            }
             Event::Event(event) => {
+                if self.chat.handle(&event, &mut self.sender) {
+                    return None;
+                }
                 if let Some(selection) = self.menu.handle(event.clone()) {
                     if selection == 2 {
-                        self.sender.send("leaveGame");
+                        self.sender.send(ClientMessage::LeaveGame);
                     } else if selection == READY_INDEX {
                         self.ready = !self.ready;
                         self.menu.sections[READY_INDEX] = ready_section(self.ready, self.play_type);
-                        self.sender.send(if self.ready { "ready" } else { "unready" });
+                        self.sender.send(if self.ready { ClientMessage::Ready } else { ClientMessage::Unready });
                     } else if selection == READY_INDEX + 1 {
                         if let codevisual::Event::MouseDown { position, .. } = event {
                             let x = position.x as f32 / self.app.window().get_size().x as f32 * 2.0 - 1.0;
@@ -103,7 +118,7 @@ impl Screen for GameLobby {
                             for c in 0..6 {
                                 let c = (c + 'A' as u8) as char;
                                 if pos.x - size.x * 0.5 <= x && x <= pos.x + size.x * 1.5 {
-                                    self.sender.send(format!("selectColor {}", c));
+                                    self.sender.send(ClientMessage::SelectColor { color: c });
                                 }
                                 pos.x += size.x * 2.0;
                             }
@@ -146,6 +161,7 @@ impl GameLobby {
             ready: false,
             players: BTreeMap::new(),
             play_type: typ,
+            chat: ChatPanel::new(app, ChatScope::Game),
             menu: MenuScreen::new(app, vec![
                 MenuSection {
                     text: String::from("TroLL InvaSioN"),
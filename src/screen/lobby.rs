@@ -7,10 +7,24 @@ pub struct Lobby {
     sender: connection::Sender,
     next_query_time: f64,
     games: BTreeMap<String, usize>,
+    maps: BTreeMap<String, (usize, String)>,
+    selected_map: usize,
+    selected_turn_time: usize,
+    chat: ChatPanel,
 }
 
-const CREATE_INDEX: usize = 6;
-const GAMES_START: usize = 9;
+const NOTICE_INDEX: usize = 3;
+const NAME_INDEX: usize = 5;
+const MAP_INDEX: usize = 6;
+const TURN_TIME_INDEX: usize = 7;
+const CREATE_INDEX: usize = 8;
+const GAMES_START: usize = 11;
+
+// None leaves it to the server, 0 means no limit.
+const TURN_TIMES: &[Option<u64>] = &[None, Some(30), Some(60), Some(120), Some(0)];
+
+const RANDOM_MAP_SIZE: usize = 4;
+const RANDOM_MAP_DENSITY: f64 = 0.25;
 
 impl Screen for Lobby {
     fn handle(&mut self, event: Event) -> Option<Box<Screen>> {
@@ -18,11 +32,24 @@ impl Screen for Lobby {
             Event::Update(delta_time) => {
                 self.next_query_time -= delta_time;
                 if self.next_query_time < 0.0 {
-                    self.sender.send("listGames");
+                    self.sender.send(ClientMessage::ListGames);
+                    if self.maps.is_empty() {
+                        self.sender.send(ClientMessage::ListMaps);
+                    }
                     self.next_query_time = 1.0;
                 }
             }
             Event::Draw(framebuffer) => {
+                self.menu.sections[MAP_INDEX].text = match self.maps.iter().nth(self.selected_map) {
+                    Some((name, &(players, ref author))) => format!("map: {} ({} players, by {})", name, players, author),
+                    None if self.maps.is_empty() => String::from("map: default"),
+                    None => String::from("map: random (2-6 players)"),
+                };
+                self.menu.sections[TURN_TIME_INDEX].text = match TURN_TIMES[self.selected_turn_time] {
+                    None => String::from("turn time: server default"),
+                    Some(0) => String::from("turn time: unlimited"),
+                    Some(seconds) => format!("turn time: {}s", seconds),
+                };
                 self.menu.sections.split_off(GAMES_START);
                 for (game, player_count) in &self.games {
                     self.menu.sections.push(MenuSection {
@@ -43,6 +70,7 @@ impl Screen for Lobby {
                     });
                 }
                 self.menu.draw(framebuffer);
+                self.chat.draw(framebuffer, 0.0, &HashMap::new());
             }
             Event::Message(message) => {
                 match message {
@@ -53,13 +81,37 @@ impl Screen for Lobby {
                             self.games.insert(name, player_count);
                         }
                     }
+                    ServerMessage::MapInfo { name, players, author } => {
+                        self.maps.insert(name, (players, author));
+                    }
                     ServerMessage::GameEntered { name, typ } => {
                         return Some(Box::new(GameLobby::new(&self.app, self.nick.clone(), name, self.sender.clone(), typ)));
                     }
+                    ServerMessage::Chat { scope, nick, text } => {
+                        self.chat.add(scope, nick, text);
+                    }
+                    ServerMessage::Rejected { reason } => {
+                        return Some(NicknameScreen::rejected(&self.app, reason));
+                    }
+                    ServerMessage::ServerError { message } => {
+                        self.games.clear();
+                        self.set_notice(message, Color::rgb(1.0, 0.5, 0.5));
+                    }
                     _ => {}
                 }
             }
+            Event::Text(c) => {
+                if !self.chat.type_char(c) {
+                    let name_section = self.name_section();
+                    if name_section.text.chars().count() < 15 {
+                        name_section.text.push(c);
+                    }
+                }
+            }
             Event::Event(event) => {
+                if self.chat.handle(&event, &mut self.sender) {
+                    return None;
+                }
                 if let codevisual::Event::KeyDown { key } = event {
                     match key {
                         codevisual::Key::Backspace => {
@@ -70,21 +122,20 @@ impl Screen for Lobby {
                                 self.create_game();
                             }
                         }
-                        _ => {
-                            let key = format!("{:?}", key);
-                            if key.len() == 1 {
-                                let name_section = self.name_section();
-                                if name_section.text.len() < 15 {
-                                    name_section.text += &key.to_lowercase();
-                                }
-                            }
-                        }
+                        _ => {}
                     }
                 } else if let Some(selection) = self.menu.handle(event.clone()) {
                     if selection == 1 {
-                        self.sender.send("-");
+                        self.sender.send(ClientMessage::Logout);
+                        self.sender.close();
                         *RECEIVER.lock().unwrap() = None;
                         return Some(Box::new(NicknameScreen::new(&self.app)));
+                    } else if selection == MAP_INDEX {
+                        if !self.maps.is_empty() {
+                            self.selected_map = (self.selected_map + 1) % (self.maps.len() + 1);
+                        }
+                    } else if selection == TURN_TIME_INDEX {
+                        self.selected_turn_time = (self.selected_turn_time + 1) % TURN_TIMES.len();
                     } else if selection == CREATE_INDEX {
                         if !self.name_section().text.is_empty() {
                             self.create_game();
@@ -111,18 +162,33 @@ impl Screen for Lobby {
 impl Lobby {
     fn create_game(&mut self) {
         let name = self.name_section().text.clone();
-        self.sender.send(format!("createGame {}", name));
+        let map = match self.maps.keys().nth(self.selected_map) {
+            Some(map) => MapChoice::Named(map.clone()),
+            None if self.maps.is_empty() => MapChoice::Default,
+            None => MapChoice::Generated {
+                seed: random::<u32>() as u64,
+                size: RANDOM_MAP_SIZE,
+                density: RANDOM_MAP_DENSITY,
+            },
+        };
+        let turn_time = TURN_TIMES[self.selected_turn_time];
+        self.sender.send(ClientMessage::CreateGame { name, map, turn_time });
     }
     fn connect(&mut self, index: usize) {
-        let game_name = self.games.keys().nth(index).unwrap();
-        self.sender.send(format!("joinGame {} player", game_name));
+        let name = self.games.keys().nth(index).unwrap().clone();
+        self.sender.send(ClientMessage::JoinGame { name, typ: PlayType::Player });
     }
     fn connect_spectator(&mut self, index: usize) {
-        let game_name = self.games.keys().nth(index).unwrap();
-        self.sender.send(format!("joinGame {} spectator", game_name));
+        let name = self.games.keys().nth(index).unwrap().clone();
+        self.sender.send(ClientMessage::JoinGame { name, typ: PlayType::Spectator });
+    }
+    fn set_notice(&mut self, text: String, color: Color) {
+        let notice = &mut self.menu.sections[NOTICE_INDEX];
+        notice.text = text;
+        notice.color = color;
     }
     fn name_section(&mut self) -> &mut MenuSection {
-        &mut self.menu.sections[CREATE_INDEX - 1]
+        &mut self.menu.sections[NAME_INDEX]
     }
     pub fn new(app: &Rc<codevisual::App>, nick: String, sender: connection::Sender) -> Self {
         Self {
@@ -161,6 +227,20 @@ impl Lobby {
                     back_color: Color::rgb(0.2, 0.2, 0.4),
                     hover_color: None,
                 },
+                MenuSection {
+                    text: String::from("map: default"),
+                    size: 5.0,
+                    color: Color::WHITE,
+                    back_color: Color::rgb(0.2, 0.2, 0.2),
+                    hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
+                },
+                MenuSection {
+                    text: String::from("turn time: server default"),
+                    size: 5.0,
+                    color: Color::WHITE,
+                    back_color: Color::rgb(0.2, 0.2, 0.2),
+                    hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
+                },
                 MenuSection {
                     text: String::from("create game"),
                     size: 10.0,
@@ -177,6 +257,10 @@ impl Lobby {
                     hover_color: None,
                 }]),
             games: BTreeMap::new(),
+            maps: BTreeMap::new(),
+            selected_map: 0,
+            selected_turn_time: 0,
+            chat: ChatPanel::new(app, ChatScope::Lobby),
         }
     }
 }
\ No newline at end of file
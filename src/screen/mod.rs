@@ -6,6 +6,12 @@ mod game;
 mod lobby;
 mod game_lobby;
 mod nickname;
+mod connection_status;
+mod notice;
+mod chat;
+mod animation;
+#[cfg(not(target_os = "emscripten"))]
+mod replay;
 
 pub use self::winner::*;
 pub use self::menu_screen::*;
@@ -13,14 +19,69 @@ pub use self::game::*;
 pub use self::lobby::*;
 pub use self::game_lobby::*;
 pub use self::nickname::*;
+pub use self::connection_status::*;
+pub use self::notice::*;
+pub use self::chat::*;
+pub use self::animation::*;
+#[cfg(not(target_os = "emscripten"))]
+pub use self::replay::*;
 
 pub enum Event<'a, 'b> where 'b: 'a {
     Update(f64),
     Draw(&'a mut ugli::Framebuffer<'b>),
     Event(codevisual::Event),
+    // A character typed on the keyboard, for text entry fields.
+    Text(char),
     Message(ServerMessage),
 }
 
 pub trait Screen {
     fn handle(&mut self, event: Event) -> Option<Box<Screen>>;
-}
\ No newline at end of file
+}
+
+#[cfg(target_os = "emscripten")]
+lazy_static! {
+    static ref TYPED: Mutex<Vec<char>> = Mutex::new(Vec::new());
+}
+
+// Collects the characters typed in the browser, so nicks, game names
+// and chat can be written in any language.
+#[cfg(target_os = "emscripten")]
+pub fn listen_for_text() {
+    let callback = webby::Callback::from(|code: i32| {
+        if let Some(c) = std::char::from_u32(code as u32) {
+            TYPED.lock().unwrap().push(c);
+        }
+    });
+    js! {
+        TrollInvasion.listenText(@callback);
+    }
+}
+
+// Characters typed since the last call.
+#[cfg(target_os = "emscripten")]
+pub fn take_typed() -> Vec<char> {
+    mem::replace(&mut *TYPED.lock().unwrap(), Vec::new())
+}
+
+// Character typed by a key press. codevisual has no text input events
+// outside the browser, so there it is guessed from the key.
+#[cfg(not(target_os = "emscripten"))]
+pub fn key_char(key: codevisual::Key) -> Option<char> {
+    if let codevisual::Key::Space = key {
+        return Some(' ');
+    }
+    let name = format!("{:?}", key);
+    if name.len() == 1 {
+        name.to_lowercase().chars().next()
+    } else if name.len() == 4 && name.starts_with("Num") {
+        name.chars().last()
+    } else {
+        match name.as_str() {
+            "Period" => Some('.'),
+            "Minus" => Some('-'),
+            "Colon" | "Semicolon" => Some(':'),
+            _ => None,
+        }
+    }
+}
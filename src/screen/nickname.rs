@@ -1,8 +1,24 @@
 use ::*;
 
+const NICK_INDEX: usize = 4;
+const PASSWORD_INDEX: usize = 6;
+const SERVER_INDEX: usize = 8;
+const ERROR_INDEX: usize = 11;
+
+fn focused_color() -> Color {
+    Color::rgb(0.2, 0.2, 0.4)
+}
+
+fn unfocused_color() -> Color {
+    Color::rgb(0.1, 0.1, 0.2)
+}
+
 pub struct NicknameScreen {
     app: Rc<codevisual::App>,
     menu: MenuScreen,
+    focus: usize,
+    // Shown masked in the password field.
+    password: String,
 }
 
 impl NicknameScreen {
@@ -18,7 +34,7 @@ impl NicknameScreen {
                     hover_color: None,
                 },
                 MenuSection::new_empty(1.0, Color::rgb(0.05, 0.05, 0.05)),
-                MenuSection::new_empty(10.0, Color::BLACK),
+                MenuSection::new_empty(5.0, Color::BLACK),
                 MenuSection {
                     text: String::from("nickname:"),
                     size: 5.0,
@@ -30,7 +46,35 @@ impl NicknameScreen {
                     text: NICK.lock().unwrap().clone(),
                     size: 10.0,
                     color: Color::WHITE,
-                    back_color: Color::rgb(0.2, 0.2, 0.4),
+                    back_color: focused_color(),
+                    hover_color: None,
+                },
+                MenuSection {
+                    text: String::from("password (empty to play as guest):"),
+                    size: 5.0,
+                    color: Color::WHITE,
+                    back_color: Color::BLACK,
+                    hover_color: None,
+                },
+                MenuSection {
+                    text: String::new(),
+                    size: 7.0,
+                    color: Color::WHITE,
+                    back_color: unfocused_color(),
+                    hover_color: None,
+                },
+                MenuSection {
+                    text: String::from("server:"),
+                    size: 5.0,
+                    color: Color::WHITE,
+                    back_color: Color::BLACK,
+                    hover_color: None,
+                },
+                MenuSection {
+                    text: format!("{}:{}", HOST.lock().unwrap(), PORT.lock().unwrap()),
+                    size: 7.0,
+                    color: Color::WHITE,
+                    back_color: unfocused_color(),
                     hover_color: None,
                 },
                 MenuSection {
@@ -39,11 +83,105 @@ impl NicknameScreen {
                     color: Color::WHITE,
                     back_color: Color::rgb(0.3, 0.3, 0.3),
                     hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
+                },
+                MenuSection {
+                    text: String::from("register"),
+                    size: 7.0,
+                    color: Color::WHITE,
+                    back_color: Color::rgb(0.2, 0.2, 0.2),
+                    hover_color: Some(Color::rgb(0.5, 0.5, 1.0)),
                 }]),
+            focus: NICK_INDEX,
+            password: String::new(),
         }
     }
     fn nick_section(&mut self) -> &mut MenuSection {
-        &mut self.menu.sections[4]
+        &mut self.menu.sections[NICK_INDEX]
+    }
+    fn set_focus(&mut self, focus: usize) {
+        self.menu.sections[self.focus].back_color = unfocused_color();
+        self.focus = focus;
+        self.menu.sections[self.focus].back_color = focused_color();
+    }
+    fn type_char(&mut self, c: char) {
+        if self.focus == PASSWORD_INDEX {
+            if self.password.chars().count() < MAX_PASSWORD_LENGTH {
+                self.password.push(c);
+            }
+        } else {
+            let field = &mut self.menu.sections[self.focus];
+            if self.focus != NICK_INDEX || field.text.chars().count() < MAX_NICK_LENGTH {
+                field.text.push(c);
+            }
+        }
+        self.update_password();
+    }
+    fn erase_char(&mut self) {
+        if self.focus == PASSWORD_INDEX {
+            self.password.pop();
+        } else {
+            self.menu.sections[self.focus].text.pop();
+        }
+        self.update_password();
+    }
+    fn update_password(&mut self) {
+        self.menu.sections[PASSWORD_INDEX].text = self.password.chars().map(|_| '*').collect();
+    }
+    fn play(&mut self, register: bool) -> Option<Box<Screen>> {
+        let nick = self.nick_section().text.clone();
+        if let Err(reason) = validate_nick(&nick) {
+            self.set_error(reason.description());
+            return None;
+        }
+        if register || !self.password.is_empty() {
+            if let Err(reason) = validate_password(&self.password) {
+                self.set_error(reason.description());
+                return None;
+            }
+        }
+        let server = self.menu.sections[SERVER_INDEX].text.trim().to_owned();
+        let (host, port) = match server.rfind(':') {
+            Some(index) => match server[index + 1..].parse() {
+                Ok(port) => (server[..index].to_owned(), port),
+                Err(_) => {
+                    self.set_error("invalid server port");
+                    return None;
+                }
+            },
+            None => (server, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            self.set_error("server can not be empty");
+            return None;
+        }
+        *HOST.lock().unwrap() = host;
+        *PORT.lock().unwrap() = port;
+        *NICK.lock().unwrap() = nick;
+        *CREDENTIALS.lock().unwrap() = if self.password.is_empty() {
+            connection::Credentials::Guest
+        } else if register {
+            connection::Credentials::Register(self.password.clone())
+        } else {
+            connection::Credentials::Password(self.password.clone())
+        };
+        Some(connect(&self.app))
+    }
+    // Screen to return to when the server rejects the connection.
+    pub fn rejected(app: &Rc<codevisual::App>, reason: RejectReason) -> Box<Screen> {
+        *RECEIVER.lock().unwrap() = None;
+        let mut screen = Self::new(app);
+        screen.set_error(reason.description());
+        Box::new(screen)
+    }
+    pub fn set_error(&mut self, error: &str) {
+        self.menu.sections.truncate(ERROR_INDEX);
+        self.menu.sections.push(MenuSection {
+            text: error.to_owned(),
+            size: 5.0,
+            color: Color::rgb(1.0, 0.5, 0.5),
+            back_color: Color::BLACK,
+            hover_color: None,
+        });
     }
 }
 
@@ -53,34 +191,31 @@ impl Screen for NicknameScreen {
             Event::Event(event) => {
                 if let codevisual::Event::KeyDown { key } = event {
                     match key {
-                        codevisual::Key::Backspace => {
-                            self.nick_section().text.pop();
-                        }
+                        codevisual::Key::Backspace => self.erase_char(),
                         codevisual::Key::Enter => {
-                            if !self.nick_section().text.is_empty() {
-                                *NICK.lock().unwrap() = self.nick_section().text.clone();
-                                return Some(connect(&self.app));
-                            }
+                            return self.play(false);
                         }
-                        _ => {
-                            let key = format!("{:?}", key);
-                            if key.len() == 1 {
-                                let nick_section = self.nick_section();
-                                if nick_section.text.len() < 15 {
-                                    nick_section.text += &key.to_lowercase();
-                                }
-                            }
+                        codevisual::Key::Tab => {
+                            let focus = match self.focus {
+                                NICK_INDEX => PASSWORD_INDEX,
+                                PASSWORD_INDEX => SERVER_INDEX,
+                                _ => NICK_INDEX,
+                            };
+                            self.set_focus(focus);
                         }
+                        _ => {}
                     }
                 } else if let Some(selection) = self.menu.handle(event) {
-                    if self.menu.sections[selection].text == "play!" {
-                        if !self.nick_section().text.is_empty() {
-                            *NICK.lock().unwrap() = self.nick_section().text.clone();
-                            return Some(connect(&self.app));
-                        }
+                    if selection == NICK_INDEX || selection == PASSWORD_INDEX || selection == SERVER_INDEX {
+                        self.set_focus(selection);
+                    } else if self.menu.sections[selection].text == "play!" {
+                        return self.play(false);
+                    } else if self.menu.sections[selection].text == "register" {
+                        return self.play(true);
                     }
                 }
             }
+            Event::Text(c) => self.type_char(c),
             Event::Draw(framebuffer) => {
                 self.menu.draw(framebuffer);
             }
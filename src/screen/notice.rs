@@ -0,0 +1,48 @@
+use ::*;
+
+const NOTICE_TIME: f64 = 10.0;
+const NOTICE_SIZE: f32 = 4.0;
+
+// Announcement from the server admin, shown on top of any screen for a while.
+pub struct Notice {
+    font: codevisual::Font,
+    menu: MenuScreen,
+    text: String,
+    time_left: f64,
+}
+
+impl Notice {
+    pub fn new(app: &Rc<codevisual::App>) -> Self {
+        Self {
+            font: codevisual::Font::new(app.ugli_context(), (include_bytes!("font.ttf") as &[u8]).to_owned()),
+            menu: MenuScreen::new(app, Vec::new()),
+            text: String::new(),
+            time_left: 0.0,
+        }
+    }
+
+    pub fn show(&mut self, text: String) {
+        self.text = text;
+        self.time_left = NOTICE_TIME;
+    }
+
+    pub fn update(&mut self, delta_time: f64) {
+        self.time_left -= delta_time;
+    }
+
+    pub fn draw(&self, framebuffer: &mut ugli::Framebuffer) {
+        if self.time_left <= 0.0 {
+            return;
+        }
+        let framebuffer_size = framebuffer.get_size();
+        let framebuffer_size = vec2(framebuffer_size.x as f32, framebuffer_size.y as f32);
+        let height = NOTICE_SIZE * 2.0 / 100.0;
+        self.menu.draw_rect(framebuffer, vec2(-1.0, 1.0), vec2(1.0, 1.0 - height * 2.0), Color::rgb(0.2, 0.2, 0.4));
+        self.font.draw_aligned(framebuffer,
+                               &self.text,
+                               vec2(framebuffer_size.x / 2.0, framebuffer_size.y * (1.0 - height * 0.75)),
+                               0.5,
+                               framebuffer_size.y * NOTICE_SIZE / 100.0,
+                               Color::rgb(1.0, 1.0, 0.5));
+    }
+}
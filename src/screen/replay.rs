@@ -0,0 +1,94 @@
+use ::*;
+
+const STATUS_SIZE: f32 = 3.0;
+const STATUS_OFFSET: f32 = 2.0;
+
+// Plays back a recorded game in the game screen.
+// Space pauses and resumes, Right steps to the next map change.
+pub struct ReplayScreen {
+    app: Rc<codevisual::App>,
+    font: codevisual::Font,
+    game: Game,
+    messages: std::collections::VecDeque<(f64, ServerMessage)>,
+    time: f64,
+    paused: bool,
+    winner: Option<String>,
+}
+
+impl ReplayScreen {
+    pub fn new(app: &Rc<codevisual::App>, messages: Vec<(f64, ServerMessage)>) -> Self {
+        Self {
+            app: app.clone(),
+            font: codevisual::Font::new(app.ugli_context(), (include_bytes!("font.ttf") as &[u8]).to_owned()),
+            game: Game::new(app, String::new(), connection::Sender::offline("")),
+            messages: messages.into_iter().collect(),
+            time: 0.0,
+            paused: false,
+            winner: None,
+        }
+    }
+
+    fn feed(&mut self, message: ServerMessage) {
+        if let ServerMessage::GameFinish { ref winner } = message {
+            self.winner = Some(winner.clone());
+            return;
+        }
+        // The replay stays on the game screen, whatever the message would switch to.
+        self.game.handle(Event::Message(message));
+    }
+
+    fn step(&mut self) {
+        self.paused = true;
+        while let Some((time, message)) = self.messages.pop_front() {
+            self.time = time;
+            let end = message == ServerMessage::EndMap;
+            self.feed(message);
+            if end {
+                break;
+            }
+        }
+    }
+
+    fn draw(&mut self, framebuffer: &mut ugli::Framebuffer) {
+        self.game.handle(Event::Draw(framebuffer));
+        let status = match self.winner {
+            Some(ref winner) => format!("replay finished, {} won", winner),
+            None if self.paused => format!("replay paused at {:.1}s (space to play, right to step)", self.time),
+            None => format!("replay {:.1}s (space to pause)", self.time),
+        };
+        let framebuffer_size = framebuffer.get_size();
+        let unit = framebuffer_size.y as f32 / 100.0;
+        self.font.draw_aligned(
+            framebuffer,
+            &status,
+            vec2(STATUS_OFFSET * unit, framebuffer_size.y as f32 - (STATUS_OFFSET + STATUS_SIZE) * unit),
+            0.0, STATUS_SIZE * unit, Color::WHITE);
+    }
+}
+
+impl Screen for ReplayScreen {
+    fn handle(&mut self, event: Event) -> Option<Box<Screen>> {
+        match event {
+            Event::Update(delta_time) => {
+                if !self.paused {
+                    self.time += delta_time;
+                    while self.messages.front().map_or(false, |&(time, _)| time <= self.time) {
+                        let (_, message) = self.messages.pop_front().unwrap();
+                        self.feed(message);
+                    }
+                }
+                self.game.handle(Event::Update(delta_time));
+            }
+            Event::Draw(framebuffer) => self.draw(framebuffer),
+            Event::Event(codevisual::Event::KeyDown { key: codevisual::Key::Space }) => {
+                self.paused = !self.paused;
+            }
+            Event::Event(codevisual::Event::KeyDown { key: codevisual::Key::Right }) => self.step(),
+            Event::Event(event) => {
+                self.game.handle(Event::Event(event));
+            }
+            Event::Text(_) | Event::Message(_) => {}
+        }
+        None
+    }
+}
@@ -56,7 +56,7 @@ impl Screen for WinnerScreen {
                     return Some(Box::new(Lobby::new(&self.app, self.nick.clone(), self.sender.clone())));
                 } else if let Some(selection) = self.menu.handle(event) {
                     if self.menu.sections[selection].text == "leave" {
-                        self.sender.send("leaveGame");
+                        self.sender.send(ClientMessage::LeaveGame);
                         return Some(Box::new(Lobby::new(&self.app, self.nick.clone(), self.sender.clone())));
                     }
                 }
@@ -1,57 +1,757 @@
 use ::*;
 
-pub fn run(port: u16) {
-    let mut jar = std::process::Command::new("java");
-    jar.arg("-jar").arg(std::path::Path::new("..").join("target").join("troll-invasion.jar"));
-    jar.stdin(std::process::Stdio::piped());
-    jar.stdout(std::process::Stdio::piped());
-    let jar = jar.spawn().expect("Failed to start troll-invasion.jar");
-    let jar_in = std::rc::Rc::new(std::cell::RefCell::new(jar.stdin.unwrap()));
-    let mut jar_out = std::io::BufReader::new(jar.stdout.unwrap());
-    let connections: std::sync::Arc<std::sync::Mutex<HashMap<String, ws::Sender>>> = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
-    std::thread::spawn({
-        let connections = connections.clone();
-        move || {
-            let mut line = String::new();
-            loop {
-                use std::io::BufRead;
-                line.clear();
-                if jar_out.read_line(&mut line).unwrap() == 0 {
-                    break;
-                }
-                let line = line.trim_right_matches('\n').trim_right_matches('\r');
-                eprintln!("> {}", line);
-                let (nicks, message) = line.split_at(line.find(':').expect("No ':' found"));
-                let message = message[1..].trim();
-                for nick in nicks.split(',') {
-                    if let Some(connection) = connections.lock().unwrap().get(nick) {
-                        connection.send(message).unwrap();
+use accounts::Accounts;
+use backend::{self, Backend};
+use limits::{CommandClass, Limiter, RateLimits, Verdict};
+use map::GameMap;
+use replay::Recorder;
+use status::{self, Status, STATUS_PATH};
+
+const MAPS_DIR: &str = "maps";
+const SHUTDOWN_DELAY: u64 = 30;
+const FLUSH_HOVER: ws::util::Token = ws::util::Token(1);
+const TICK_INTERVAL: u64 = 250;
+
+const CONSOLE_HELP: &str = "\
+Commands (names with spaces can be quoted):
+  nicks                    list players and held seats
+  games                    list games and who is in them
+  kick <nick>              disconnect a player and end their session
+  close <game>             send everyone in a game back to the lobby
+  say <text>               announce to everyone
+  say-game <game> <text>   announce to everyone in a game
+  stats                    show message counters of every connection
+  guests on|off            allow or forbid playing without an account
+  shutdown [seconds]       warn everyone, then stop the server";
+
+fn route(connections: &Mutex<HashMap<String, ws::Sender>>, lines: Vec<String>) {
+    for line in lines {
+        eprintln!("> {}", line);
+        let (nicks, message) = line.split_at(find_unquoted(&line, ':').expect("No ':' found"));
+        let message = message[1..].trim();
+        for nick in split_unquoted(nicks, ',') {
+            if let Some(connection) = connections.lock().unwrap().get(&unquote(nick)) {
+                connection.send(message).unwrap();
+            }
+        }
+    }
+}
+
+// Records the lines concerning games that are being played. Only lines sent to the
+// whole room are kept: the state sent to a spectator joining or a player resuming
+// mid-game would otherwise be replayed as if it happened again.
+fn record(recorder: &mut Recorder, backend: &Backend, nick: &str, input: &str, output: &[String]) {
+    if let Some(game) = backend.game_of(nick) {
+        recorder.record(game, &format!("< {}", input));
+    }
+    for line in output {
+        let (nicks, message) = line.split_at(find_unquoted(line, ':').expect("No ':' found"));
+        let nicks: Vec<String> = split_unquoted(nicks, ',').into_iter().map(unquote).collect();
+        let game = nicks.iter().filter_map(|nick| backend.game_of(nick)).next();
+        if let Some(game) = game {
+            if !backend.members(game).iter().all(|member| nicks.contains(member)) {
+                continue;
+            }
+            let message = ServerMessage::parse(message[1..].trim());
+            if let Ok(ServerMessage::GameStart) = message {
+                recorder.start(game);
+            }
+            recorder.record(game, &format!("> {}", line));
+            if let Ok(ServerMessage::GameFinish { .. }) = message {
+                recorder.stop(game);
+            }
+        }
+    }
+    recorder.retain(|game| backend.is_playing(game));
+}
+
+fn reject(connection: &ws::Sender, reason: RejectReason) {
+    eprintln!("Rejecting connection: {}", reason);
+    connection.send(ServerMessage::Rejected { reason }.to_wire()).unwrap();
+    connection.close(ws::CloseCode::Policy).unwrap();
+}
+
+fn announce(connections: &Mutex<HashMap<String, ws::Sender>>, nicks: &[String], text: &str) {
+    let mut output = Vec::new();
+    backend::send(&mut output, nicks, ServerMessage::System { text: text.to_owned() });
+    route(connections, output);
+}
+
+fn new_token() -> String {
+    format!("{:016x}{:016x}", random::<u64>(), random::<u64>())
+}
+
+struct Session {
+    token: String,
+    connected: bool,
+}
+
+// A session can be resumed with its token even while it still looks connected,
+// since the client often notices a dropped connection before the server does.
+fn check_resume(sessions: &HashMap<String, Session>, nick: &str, token: &str) -> Result<(), RejectReason> {
+    match sessions.get(nick) {
+        Some(session) if session.token == token => Ok(()),
+        _ => Err(RejectReason::SessionExpired),
+    }
+}
+
+// Everything shared between connections and the session expiry timers.
+struct State {
+    started: std::time::Instant,
+    backend: Backend,
+    backend_started: std::time::Instant,
+    crashes: usize,
+    recorder: Recorder,
+    sessions: HashMap<String, Session>,
+    limits: RateLimits,
+    // Rate limiters by connection id.
+    limiters: HashMap<u32, Limiter>,
+    accounts: Accounts,
+    guests: bool,
+    turn_time: u64,
+}
+
+impl State {
+    fn handle(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>, nick: &str, message: ClientMessage) {
+        // Logged and recorded without passwords or session tokens.
+        let input = format!("{}:{}", quote(nick), message.to_log());
+        eprintln!("< {}", input);
+        self.run_backend(connections, nick, &input, |backend| backend.handle(nick, message));
+    }
+
+    fn tick(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>) {
+        self.run_backend(connections, "", "", |backend| backend.tick());
+    }
+
+    fn run_backend<F: FnOnce(&mut Backend) -> Vec<String>>(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>,
+                                                           nick: &str, input: &str, f: F) {
+        let output = {
+            let backend = &mut self.backend;
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(backend)))
+        };
+        match output {
+            Ok(output) => {
+                record(&mut self.recorder, &self.backend, nick, input, &output);
+                route(connections, output);
+            }
+            Err(_) => self.restart_backend(connections),
+        }
+    }
+
+    fn status(&self, connections: &Mutex<HashMap<String, ws::Sender>>) -> Status {
+        Status {
+            players: connections.lock().unwrap().len(),
+            uptime: self.started.elapsed().as_secs(),
+            backend_uptime: self.backend_started.elapsed().as_secs(),
+            crashes: self.crashes,
+            games: self.backend.games(),
+        }
+    }
+
+    fn logout(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>, nick: &str) {
+        if self.sessions.remove(nick).is_some() {
+            self.handle(connections, nick, ClientMessage::Logout);
+        }
+    }
+
+    // Replaces a crashed backend with a fresh one. All games are lost,
+    // so everyone is sent back to the lobby and told what happened.
+    fn restart_backend(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>) {
+        self.crashes += 1;
+        eprintln!("Backend crashed after running for {} seconds, restarting (crash #{})",
+                  self.backend_started.elapsed().as_secs(), self.crashes);
+        let nicks: Vec<String> = connections.lock().unwrap().keys().cloned().collect();
+        let in_game: Vec<String> = nicks.iter().filter(|nick| self.backend.game_of(nick).is_some()).cloned().collect();
+        self.recorder.retain(|_| false);
+        self.sessions.retain(|_, session| session.connected);
+        self.backend = Backend::new(GameMap::load_all(MAPS_DIR), self.turn_time);
+        self.backend_started = std::time::Instant::now();
+        let mut output = Vec::new();
+        for nick in &nicks {
+            output.extend(self.backend.handle(nick, ClientMessage::Login { nick: nick.clone() }));
+        }
+        for nick in in_game {
+            backend::send(&mut output, &[nick.clone()], ServerMessage::GameLeft { nick });
+        }
+        backend::send(&mut output, &nicks, ServerMessage::ServerError {
+            message: String::from("server error, all games were closed"),
+        });
+        route(connections, output);
+        eprintln!("Backend restarted, {} players logged back in", nicks.len());
+    }
+
+    fn kick(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>, nick: &str) -> bool {
+        if !self.sessions.contains_key(nick) {
+            return false;
+        }
+        if let Some(connection) = connections.lock().unwrap().remove(nick) {
+            reject(&connection, RejectReason::Kicked);
+        }
+        self.logout(connections, nick);
+        true
+    }
+
+    fn close_game(&mut self, connections: &Mutex<HashMap<String, ws::Sender>>, name: &str) -> bool {
+        if !self.backend.games().iter().any(|game| game.name == name) {
+            return false;
+        }
+        let output = self.backend.close_game(name);
+        let backend = &self.backend;
+        self.recorder.retain(|game| backend.is_playing(game));
+        route(connections, output);
+        true
+    }
+}
+
+struct Handler {
+    connection: ws::Sender,
+    state: Arc<Mutex<State>>,
+    connections: Arc<Mutex<HashMap<String, ws::Sender>>>,
+    grace: u64,
+    nick: String,
+    greeted: bool,
+    // Latest hover that was over the limit, sent once the limit allows.
+    pending_hover: Option<ClientMessage>,
+    flush_scheduled: bool,
+}
+
+impl Handler {
+    fn check_limit(&mut self, message: &ClientMessage) -> Verdict {
+        let id = self.connection.connection_id();
+        let mut state = self.state.lock().unwrap();
+        match state.limiters.get_mut(&id) {
+            Some(limiter) => limiter.check(CommandClass::of(message)),
+            None => Verdict::Allow,
+        }
+    }
+
+    fn flush_hover(&mut self) -> ws::Result<()> {
+        if self.pending_hover.is_none() {
+            return Ok(());
+        }
+        let id = self.connection.connection_id();
+        let ready = match self.state.lock().unwrap().limiters.get_mut(&id) {
+            Some(limiter) => limiter.flush_hover(),
+            None => Ok(()),
+        };
+        match ready {
+            Ok(()) => {
+                let message = self.pending_hover.take().unwrap();
+                if !self.nick.is_empty() {
+                    self.send_to_backend(message);
+                }
+            }
+            Err(wait) => {
+                let millis = wait.as_secs() * 1000 + wait.subsec_nanos() as u64 / 1_000_000;
+                self.flush_scheduled = true;
+                self.connection.timeout(std::cmp::max(millis, 1), FLUSH_HOVER)?;
+            }
+        }
+        Ok(())
+    }
+
+
+    fn send_to_backend(&mut self, message: ClientMessage) {
+        if !self.owns_nick(&self.connections.lock().unwrap()) {
+            eprintln!("Message from kicked {:?}: {}", self.nick, message.to_log());
+            return;
+        }
+        self.state.lock().unwrap().handle(&self.connections, &self.nick, message);
+    }
+
+    fn start_session(&mut self, state: &mut State, nick: &str) {
+        let token = new_token();
+        state.sessions.insert(nick.to_owned(), Session {
+            token: token.clone(),
+            connected: true,
+        });
+        self.nick = nick.to_owned();
+        self.connections.lock().unwrap().insert(self.nick.clone(), self.connection.clone());
+        self.connection.send(ServerMessage::Session { token, grace: self.grace }.to_wire()).unwrap();
+    }
+
+    // Logs in with a guest nick, or to an account if a password is given.
+    // With `register` set, the account is created first.
+    fn login(&mut self, nick: &str, password: Option<&str>, register: bool) -> Result<(), RejectReason> {
+        if !self.nick.is_empty() {
+            eprintln!("{:?} tried to log in again as {:?}", self.nick, nick);
+            return Ok(());
+        }
+        validate_nick(nick)?;
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        // A disconnected player's nick stays taken while their seat is held.
+        if state.sessions.contains_key(nick) {
+            return Err(RejectReason::NickTaken);
+        }
+        match password {
+            Some(password) if register => {
+                validate_password(password)?;
+                state.accounts.register(nick, password)?;
+                eprintln!("Registered account {:?}", nick);
+            }
+            Some(password) => {
+                if !state.accounts.verify(nick, password) {
+                    return Err(RejectReason::WrongPassword);
+                }
+            }
+            None => {
+                if state.accounts.is_registered(nick) {
+                    return Err(RejectReason::NickRegistered);
+                }
+                if !state.guests {
+                    return Err(RejectReason::GuestsDisabled);
+                }
+            }
+        }
+        self.start_session(&mut state, nick);
+        state.handle(&self.connections, nick, ClientMessage::Login { nick: nick.to_owned() });
+        Ok(())
+    }
+
+    fn resume(&mut self, nick: &str, token: &str) -> Result<(), RejectReason> {
+        if !self.nick.is_empty() {
+            eprintln!("{:?} tried to resume session of {:?}", self.nick, nick);
+            return Ok(());
+        }
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        check_resume(&state.sessions, nick, token)?;
+        eprintln!("Resuming session of {:?}", nick);
+        // The old connection, if still open, is closed. It no longer owns
+        // the nick, so its handler leaves the session alone when it closes.
+        if let Some(old) = self.connections.lock().unwrap().remove(nick) {
+            eprintln!("Closing the old connection of {:?}", nick);
+            let _ = old.close(ws::CloseCode::Away);
+        }
+        self.start_session(&mut state, nick);
+        state.handle(&self.connections, nick, ClientMessage::Resume {
+            nick: nick.to_owned(),
+            token: token.to_owned(),
+        });
+        Ok(())
+    }
+
+    // Whether the nick of this connection is still its own. A kicked connection
+    // keeps its nick until it closes, and by then someone else may have taken it.
+    fn owns_nick(&self, connections: &HashMap<String, ws::Sender>) -> bool {
+        connections.get(&self.nick).map_or(false, |connection| connection.connection_id() == self.connection.connection_id())
+    }
+
+    // Forgets the nick of this connection, returning it if it was still
+    // its own and so the session should be ended.
+    fn take_nick(&mut self) -> Option<String> {
+        if self.nick.is_empty() {
+            return None;
+        }
+        let nick = {
+            let mut connections = self.connections.lock().unwrap();
+            if !self.owns_nick(&connections) {
+                None
+            } else {
+                connections.remove(&self.nick);
+                Some(self.nick.clone())
+            }
+        };
+        self.nick.clear();
+        nick
+    }
+
+    // Forgets the nick of this connection and removes the player from the backend.
+    fn logout(&mut self) {
+        if let Some(nick) = self.take_nick() {
+            self.state.lock().unwrap().logout(&self.connections, &nick);
+        }
+    }
+
+    // Keeps the player in the backend for the grace period,
+    // logging them out unless the session is resumed by then.
+    fn disconnect(&mut self) {
+        if self.nick.is_empty() {
+            return;
+        }
+        if self.grace == 0 {
+            self.logout();
+            return;
+        }
+        let nick = match self.take_nick() {
+            Some(nick) => nick,
+            // Kicked, the session is already over.
+            None => return,
+        };
+        let token = {
+            let mut state = self.state.lock().unwrap();
+            match state.sessions.get_mut(&nick) {
+                Some(session) => {
+                    session.connected = false;
+                    session.token.clone()
+                }
+                // Already logged out, e.g. kicked by the admin.
+                None => return,
+            }
+        };
+        eprintln!("Holding the seat of {:?} for {} seconds", nick, self.grace);
+        let state = self.state.clone();
+        let connections = self.connections.clone();
+        let grace = std::time::Duration::from_secs(self.grace);
+        thread::spawn(move || {
+            thread::sleep(grace);
+            let mut state = state.lock().unwrap();
+            let expired = state.sessions.get(&nick).map_or(false, |session| !session.connected && session.token == token);
+            if expired {
+                eprintln!("Session of {:?} expired", nick);
+                state.logout(&connections, &nick);
+            }
+        });
+    }
+}
+
+impl ws::Handler for Handler {
+    // Plain HTTP requests for the status are answered instead of upgrading to a websocket.
+    fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+        if request.resource() != STATUS_PATH {
+            return ws::Response::from_request(request);
+        }
+        let body = {
+            let mut state = self.state.lock().unwrap();
+            state.limiters.remove(&self.connection.connection_id());
+            state.status(&self.connections).to_json()
+        };
+        let mut response = ws::Response::new(200, "OK", body.into_bytes());
+        response.headers_mut().push((String::from("Content-Type"), b"application/json".to_vec()));
+        response.headers_mut().push((String::from("Access-Control-Allow-Origin"), b"*".to_vec()));
+        Ok(response)
+    }
+
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        let message = match message.into_text() {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Non-text message from {:?}: {}", self.nick, e);
+                return Ok(());
+            }
+        };
+        let message = match ClientMessage::parse(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Bad message from {:?}: {}", self.nick, e);
+                return Ok(());
+            }
+        };
+        match self.check_limit(&message) {
+            Verdict::Allow => {
+                if CommandClass::of(&message) == CommandClass::Hover {
+                    self.pending_hover = None;
+                }
+            }
+            Verdict::Coalesce => {
+                self.pending_hover = Some(message);
+                if !self.flush_scheduled {
+                    self.flush_hover()?;
+                }
+                return Ok(());
+            }
+            Verdict::Drop { warn } => {
+                eprintln!("Dropping message from {:?} over the rate limit: {}", self.nick, message.to_log());
+                if warn {
+                    self.connection.send(ServerMessage::System {
+                        text: String::from("you are sending too many messages, slow down"),
+                    }.to_wire())?;
+                }
+                return Ok(());
+            }
+            Verdict::Disconnect => {
+                eprintln!("Disconnecting {:?} for flooding", self.nick);
+                self.logout();
+                reject(&self.connection, RejectReason::Flooding);
+                return Ok(());
+            }
+        }
+        if let ClientMessage::Hello { version, .. } = message {
+            eprintln!("< {}", message.to_wire());
+            if version < MIN_PROTOCOL_VERSION {
+                reject(&self.connection, RejectReason::ClientTooOld);
+            } else {
+                self.greeted = true;
+                self.connection.send(ServerMessage::Welcome {
+                    version: PROTOCOL_VERSION,
+                    capabilities: CAPABILITIES.iter().map(|&capability| capability.to_owned()).collect(),
+                }.to_wire())?;
+            }
+            return Ok(());
+        } else if !self.greeted {
+            reject(&self.connection, RejectReason::ClientTooOld);
+            return Ok(());
+        }
+        let result = match message {
+            ClientMessage::Login { ref nick } => self.login(nick, None, false),
+            ClientMessage::Authenticate { ref nick, ref password } => self.login(nick, Some(password), false),
+            ClientMessage::Register { ref nick, ref password } => self.login(nick, Some(password), true),
+            ClientMessage::Resume { ref nick, ref token } => self.resume(nick, token),
+            ClientMessage::Logout => {
+                self.logout();
+                Ok(())
+            }
+            _ => {
+                if self.nick.is_empty() {
+                    eprintln!("Message before login: {}", message.to_log());
+                } else {
+                    self.send_to_backend(message);
+                }
+                Ok(())
+            }
+        };
+        if let Err(reason) = result {
+            reject(&self.connection, reason);
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+        if event == FLUSH_HOVER {
+            self.flush_scheduled = false;
+            self.flush_hover()?;
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+        eprintln!("Connection of {:?} closed ({:?}) {}", self.nick, code, reason);
+        self.disconnect();
+        self.state.lock().unwrap().limiters.remove(&self.connection.connection_id());
+    }
+
+    fn on_error(&mut self, e: ws::Error) {
+        eprintln!("Connection of {:?} failed: {}", self.nick, e);
+        self.disconnect();
+        self.state.lock().unwrap().limiters.remove(&self.connection.connection_id());
+    }
+}
+
+fn shutdown(state: &Mutex<State>, connections: &Mutex<HashMap<String, ws::Sender>>, server: &ws::Sender, seconds: u64) {
+    eprintln!("Shutting down in {} seconds", seconds);
+    let mut left = seconds;
+    while left > 0 {
+        if left == seconds || left % 10 == 0 || left <= 5 {
+            let nicks: Vec<String> = connections.lock().unwrap().keys().cloned().collect();
+            announce(connections, &nicks, &format!("server shutting down in {} seconds", left));
+        }
+        thread::sleep(std::time::Duration::from_secs(1));
+        left -= 1;
+    }
+    eprintln!("Shutting down");
+    // The state outlives the server in other threads, so the replays are finished here.
+    state.lock().unwrap().recorder.retain(|_| false);
+    server.shutdown().unwrap();
+}
+
+// Reads admin commands from stdin until it is closed.
+fn console(state: Arc<Mutex<State>>, connections: Arc<Mutex<HashMap<String, ws::Sender>>>, server: ws::Sender) {
+    use std::io::BufRead;
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to read console input: {}", e);
+                return;
+            }
+        };
+        let args = match split_args(&line) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("Bad command {:?}: {:?}", line, e);
+                continue;
+            }
+        };
+        let command = match args.first() {
+            Some(command) => command.as_str(),
+            None => continue,
+        };
+        match (command, args.len()) {
+            ("nicks", 1) => {
+                let state = state.lock().unwrap();
+                let mut nicks: Vec<&String> = state.sessions.keys().collect();
+                nicks.sort();
+                for nick in nicks {
+                    let game = match state.backend.game_of(nick) {
+                        Some(game) => format!(" in {:?}", game),
+                        None => String::new(),
+                    };
+                    let held = if state.sessions[nick].connected { "" } else { " (disconnected)" };
+                    eprintln!("{:?}{}{}", nick, game, held);
+                }
+                eprintln!("{} players", state.sessions.len());
+            }
+            ("games", 1) => {
+                let games = state.lock().unwrap().backend.games();
+                for game in &games {
+                    eprintln!("{:?} ({}): players {:?}, spectators {:?}",
+                              game.name, game.phase, game.players, game.spectators);
+                }
+                eprintln!("{} games", games.len());
+            }
+            ("kick", 2) => {
+                if state.lock().unwrap().kick(&connections, &args[1]) {
+                    eprintln!("Kicked {:?}", args[1]);
+                } else {
+                    eprintln!("No player {:?}", args[1]);
+                }
+            }
+            ("close", 2) => {
+                if state.lock().unwrap().close_game(&connections, &args[1]) {
+                    eprintln!("Closed game {:?}", args[1]);
+                } else {
+                    eprintln!("No game {:?}", args[1]);
+                }
+            }
+            ("say", n) if n > 1 => {
+                let nicks: Vec<String> = connections.lock().unwrap().keys().cloned().collect();
+                announce(&connections, &nicks, &args[1..].join(" "));
+            }
+            ("say-game", n) if n > 2 => {
+                let game = state.lock().unwrap().backend.games().into_iter().find(|game| game.name == args[1]);
+                match game {
+                    Some(game) => {
+                        let mut nicks = game.players;
+                        nicks.extend(game.spectators);
+                        announce(&connections, &nicks, &args[2..].join(" "));
                     }
+                    None => eprintln!("No game {:?}", args[1]),
+                }
+            }
+            ("stats", 1) => {
+                let state = state.lock().unwrap();
+                let nicks: HashMap<u32, String> = connections.lock().unwrap().iter()
+                    .map(|(nick, connection)| (connection.connection_id(), nick.clone()))
+                    .collect();
+                let mut ids: Vec<&u32> = state.limiters.keys().collect();
+                ids.sort();
+                for id in ids {
+                    let limiter = &state.limiters[id];
+                    let name = match nicks.get(id) {
+                        Some(nick) => format!("{:?}", nick),
+                        None => String::from("(not logged in)"),
+                    };
+                    eprintln!("#{} {}: received {}, coalesced {}, dropped {}",
+                              id, name, limiter.received, limiter.coalesced, limiter.dropped);
                 }
+                eprintln!("{} connections", state.limiters.len());
             }
+            ("guests", 2) if args[1] == "on" || args[1] == "off" => {
+                let guests = args[1] == "on";
+                state.lock().unwrap().guests = guests;
+                eprintln!("Guests are {}", if guests { "allowed" } else { "not allowed" });
+            }
+            ("shutdown", 1) => shutdown(&state, &connections, &server, SHUTDOWN_DELAY),
+            ("shutdown", 2) => match args[1].parse() {
+                Ok(seconds) => shutdown(&state, &connections, &server, seconds),
+                Err(_) => eprintln!("Bad number of seconds {:?}", args[1]),
+            },
+            _ => eprintln!("{}", CONSOLE_HELP),
         }
-    });
-    ws::listen(("0.0.0.0", port), move |connection| {
-        let jar_in = jar_in.clone();
-        let nick = RefCell::new(String::new());
+    }
+}
+
+pub struct Options {
+    pub port: u16,
+    // Seconds a disconnected player keeps their seat.
+    pub grace: u64,
+    pub limits: RateLimits,
+    pub guests: bool,
+    // The status is always served at STATUS_PATH on the websocket port, and here too if given.
+    pub status_port: Option<u16>,
+    // Turn time in seconds for games that don't choose one, 0 for no limit.
+    pub turn_time: u64,
+    pub accounts_file: std::path::PathBuf,
+    pub replay_dir: std::path::PathBuf,
+}
+
+pub fn run(options: Options) {
+    let Options { port, grace, limits, guests, status_port, turn_time, accounts_file, replay_dir } = options;
+    let accounts = match Accounts::load(&accounts_file) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("Failed to load accounts from {:?}: {}", accounts_file, e);
+            std::process::exit(1);
+        }
+    };
+    let state = Arc::new(Mutex::new(State {
+        started: std::time::Instant::now(),
+        backend: Backend::new(GameMap::load_all(MAPS_DIR), turn_time),
+        backend_started: std::time::Instant::now(),
+        crashes: 0,
+        recorder: Recorder::new(replay_dir),
+        sessions: HashMap::new(),
+        limits,
+        limiters: HashMap::new(),
+        accounts,
+        guests,
+        turn_time,
+    }));
+    let connections = Arc::new(Mutex::new(HashMap::new()));
+    let socket = ws::WebSocket::new({
+        let state = state.clone();
         let connections = connections.clone();
-        move |message: ws::Message| {
-            let mut message = message.into_text().unwrap();
-            if message.starts_with('+') {
-                assert!(nick.borrow().len() == 0);
-                *nick.borrow_mut() = message[1..].to_owned();
-                connections.lock().unwrap().insert(nick.borrow().clone(), connection.clone());
-            } else {
-                assert!(nick.borrow().len() != 0);
-                message = format!("{}:{}", &nick.borrow(), message);
+        move |connection: ws::Sender| {
+            {
+                let mut state = state.lock().unwrap();
+                let limiter = Limiter::new(&state.limits);
+                state.limiters.insert(connection.connection_id(), limiter);
+            }
+            Handler {
+                connection,
+                state: state.clone(),
+                connections: connections.clone(),
+                grace,
+                nick: String::new(),
+                greeted: false,
+                pending_hover: None,
+                flush_scheduled: false,
             }
-            eprintln!("< {}", message);
-            use std::io::Write;
-            let mut jar_in = jar_in.borrow_mut();
-            jar_in.write(message.as_bytes()).unwrap();
-            jar_in.write("\n".as_bytes()).unwrap();
-            jar_in.flush().unwrap();
-            Ok(())
         }
     }).unwrap();
-}
\ No newline at end of file
+    let server = socket.broadcaster();
+    thread::spawn({
+        let state = state.clone();
+        let connections = connections.clone();
+        move || loop {
+            thread::sleep(std::time::Duration::from_millis(TICK_INTERVAL));
+            state.lock().unwrap().tick(&connections);
+        }
+    });
+    if let Some(status_port) = status_port {
+        let state = state.clone();
+        let connections = connections.clone();
+        thread::spawn(move || status::serve(status_port, || state.lock().unwrap().status(&connections).to_json()));
+    }
+    thread::spawn(move || console(state, connections, server));
+    socket.listen(("0.0.0.0", port)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(connected: bool) -> HashMap<String, Session> {
+        let mut sessions = HashMap::new();
+        sessions.insert(String::from("a"), Session { token: String::from("token"), connected });
+        sessions
+    }
+
+    #[test]
+    fn resume_after_disconnect() {
+        assert_eq!(check_resume(&sessions(false), "a", "token"), Ok(()));
+    }
+
+    #[test]
+    fn resume_while_connected() {
+        assert_eq!(check_resume(&sessions(true), "a", "token"), Ok(()));
+    }
+
+    #[test]
+    fn resume_needs_the_token() {
+        assert_eq!(check_resume(&sessions(true), "a", "other"), Err(RejectReason::SessionExpired));
+        assert_eq!(check_resume(&sessions(false), "b", "token"), Err(RejectReason::SessionExpired));
+    }
+}
@@ -0,0 +1,94 @@
+use ::*;
+
+use backend::GameInfo;
+
+pub const STATUS_PATH: &str = "/status";
+
+// Snapshot of the server for monitoring.
+pub struct Status {
+    pub players: usize,
+    pub uptime: u64,
+    pub backend_uptime: u64,
+    pub crashes: usize,
+    pub games: Vec<GameInfo>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn json_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+    format!("[{}]", items.join(","))
+}
+
+impl Status {
+    pub fn to_json(&self) -> String {
+        let games: Vec<String> = self.games.iter().map(|game| {
+            format!("{{\"name\":{},\"phase\":{},\"turn\":{},\"players\":{},\"spectators\":{}}}",
+                    json_string(&game.name),
+                    json_string(game.phase),
+                    game.turn.as_ref().map_or(String::from("null"), |nick| json_string(nick)),
+                    json_list(&game.players),
+                    json_list(&game.spectators))
+        }).collect();
+        format!("{{\"players\":{},\"uptime\":{},\"backend\":{{\"uptime\":{},\"crashes\":{}}},\"games\":[{}]}}",
+                self.players, self.uptime, self.backend_uptime, self.crashes, games.join(","))
+    }
+}
+
+// Serves the status on its own port, for when the websocket port is not reachable.
+pub fn serve<F: Fn() -> String>(port: u16, status: F) {
+    use std::io::{BufRead, Write};
+    let listener = match std::net::TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to serve status on port {}: {}", port, e);
+            return;
+        }
+    };
+    eprintln!("Serving status on port {}", port);
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Status connection failed: {}", e);
+                continue;
+            }
+        };
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+        let mut request = String::new();
+        {
+            let mut reader = std::io::BufReader::new(&stream);
+            if reader.read_line(&mut request).is_err() {
+                continue;
+            }
+            // Skips the headers.
+            let mut line = String::new();
+            while reader.read_line(&mut line).map(|read| read > 2).unwrap_or(false) {
+                line.clear();
+            }
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        let response = if request.starts_with("GET ") && (path == STATUS_PATH || path == "/") {
+            let body = status();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        } else {
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        };
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            eprintln!("Failed to send status: {}", e);
+        }
+    }
+}
@@ -1,37 +1,129 @@
 var socket;
-var nickname;
 
-var TrollInvasion = {
-    connect: function (host, port, nick, handler) {
-        nickname = nick;
+var SESSION_KEY = "trollInvasionSession";
+
+function loadSession() {
+    try {
+        return JSON.parse(localStorage.getItem(SESSION_KEY));
+    } catch (e) {
+        return null;
+    }
+}
+
+function saveSession(session) {
+    localStorage.setItem(SESSION_KEY, JSON.stringify(session));
+}
+
+// A session can be resumed until its grace period after the disconnect runs out.
+function resumableSession(nick) {
+    var session = loadSession();
+    if (session === null || session.nick !== nick) {
+        return null;
+    }
+    if (session.closedAt !== undefined && Date.now() - session.closedAt >= session.grace * 1000) {
+        return null;
+    }
+    return session;
+}
+
+window.addEventListener("beforeunload", function () {
+    var session = loadSession();
+    if (session !== null && session.closedAt === undefined) {
+        session.closedAt = Date.now();
+        saveSession(session);
+    }
+});
 
+var TrollInvasion = {
+    connect: function (host, port, hello, nick, login, handler, events) {
+        TrollInvasion.close();
         var buf_addr = 0;
         var buf_len = 0;
 
-        function sendLine(line) {
-            if (line.length + 1 > buf_len) {
+        function sendLine(callback, line) {
+            var len = Module.lengthBytesUTF8(line) + 1;
+            if (len > buf_len) {
                 if (buf_len !== 0) {
                     Module._free(buf_addr);
                 }
-                buf_len = line.length + 1;
+                buf_len = len;
                 buf_addr = Module._malloc(buf_len);
             }
-            Module.writeAsciiToMemory(line, buf_addr);
-            handler(buf_addr);
+            Module.stringToUTF8(line, buf_addr, buf_len);
+            callback(buf_addr);
         }
 
-        socket = new WebSocket("ws://" + host + ":" + port);
-        socket.onopen = function (e) {
-            socket.send("+" + nickname);
-        };
-        socket.onmessage = function (e) {
-            sendLine(e.data);
+        TrollInvasion.open = function (auth) {
+            socket = new WebSocket("ws://" + host + ":" + port);
+            socket.onopen = function (e) {
+                socket.send(hello);
+                socket.send(auth);
+                sendLine(events, "open");
+            };
+            socket.onmessage = function (e) {
+                sendLine(handler, e.data);
+            };
+            socket.onclose = function (e) {
+                var session = loadSession();
+                if (session !== null && session.closedAt === undefined) {
+                    session.closedAt = Date.now();
+                    saveSession(session);
+                }
+                sendLine(events, "close");
+            };
         };
+
+        var session = resumableSession(nick);
+        TrollInvasion.open(session !== null && session.closedAt !== undefined ? session.resume : login);
+    },
+    reconnect: function (auth, delay) {
+        clearTimeout(TrollInvasion.timeout);
+        TrollInvasion.auth = auth;
+        TrollInvasion.timeout = setTimeout(function () {
+            TrollInvasion.timeout = undefined;
+            TrollInvasion.open(auth);
+        }, delay);
+    },
+    retryNow: function () {
+        if (TrollInvasion.timeout !== undefined) {
+            clearTimeout(TrollInvasion.timeout);
+            TrollInvasion.timeout = undefined;
+            TrollInvasion.open(TrollInvasion.auth);
+        }
     },
     send: function (message) {
-        socket.send(message);
+        if (socket !== undefined) {
+            socket.send(message);
+        }
+    },
+    // Closes the socket without telling the game, so it does not reconnect.
+    close: function () {
+        clearTimeout(TrollInvasion.timeout);
+        TrollInvasion.timeout = undefined;
+        if (socket !== undefined) {
+            socket.onopen = null;
+            socket.onmessage = null;
+            socket.onclose = null;
+            socket.close();
+            socket = undefined;
+        }
+    },
+    setSession: function (nick, resume, grace) {
+        saveSession({nick: nick, resume: resume, grace: grace});
+    },
+    clearSession: function () {
+        localStorage.removeItem(SESSION_KEY);
+    },
+    // Passes the code point of every character typed on the page to the handler,
+    // whatever the keyboard layout. AltGr is reported as Ctrl+Alt on some systems.
+    listenText: function (handler) {
+        window.addEventListener("keydown", function (e) {
+            var chars = Array.from(e.key);
+            if (chars.length === 1 && !e.metaKey && (!e.ctrlKey || e.altKey)) {
+                handler(chars[0].codePointAt(0));
+            }
+        });
     }
 };
 
 window.TrollInvasion = TrollInvasion;
-